@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use candid::{Nat, Principal};
+
+use crate::ledger;
+use crate::state;
+use crate::types::{Campaign, CampaignStatus, FaucetBalance, FaucetError};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+thread_local! {
+    // Latest balance check per campaign. Heap only: the monitor checks again
+    // as soon as it is restarted after an upgrade.
+    static CHECKED: RefCell<BTreeMap<u64, FaucetBalance>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+impl FaucetBalance {
+    // What `balance` on `ledger` means for `campaign`, whose payouts are
+    // charged `fee`
+    fn of(campaign: &Campaign, ledger: Principal, balance: Nat, fee: u64, now: u64) -> Self {
+        let per_claim = campaign.amount.saturating_add(fee);
+        let claims_covered = match per_claim {
+            0 => u64::MAX,
+            per_claim => u64::try_from((balance.clone() / per_claim).0).unwrap_or(u64::MAX),
+        };
+        let low = campaign
+            .low_balance
+            .is_some_and(|alarm| balance < alarm.threshold);
+        Self {
+            campaign_id: campaign.id,
+            ledger,
+            balance,
+            per_claim,
+            claims_covered,
+            low,
+            checked_at: now,
+        }
+    }
+}
+
+// Asks a campaign's ledger for the faucet's balance, raising the campaign's
+// alarm if it is low
+pub async fn check(campaign_id: u64) -> Result<FaucetBalance, FaucetError> {
+    let campaign = state::read_campaign(campaign_id, |campaign| Ok(campaign.clone()))?;
+    let ledger = campaign
+        .ledger_canister_id
+        .ok_or(FaucetError::LedgerNotConfigured)?;
+    let balance = ledger::balance(ledger, campaign.ledger_kind).await?;
+    let fee = ledger::fee(ledger, campaign.ledger_kind, campaign.transfer_fee).await?;
+    let now = ic_cdk::api::time();
+    let checked = FaucetBalance::of(&campaign, ledger, balance, fee, now);
+    if checked.low {
+        // Rechecked, since the campaign may have changed during the calls
+        let _ = state::mutate_campaign(campaign_id, |campaign| {
+            if campaign.status == CampaignStatus::Active
+                && campaign.low_balance.is_some_and(|alarm| alarm.pause)
+            {
+                campaign.status = CampaignStatus::Paused;
+            }
+            Ok(())
+        });
+    }
+    CHECKED.with(|checked_balances| {
+        checked_balances
+            .borrow_mut()
+            .insert(campaign_id, checked.clone())
+    });
+    Ok(checked)
+}
+
+pub fn latest(campaign_id: u64) -> Option<FaucetBalance> {
+    CHECKED.with(|checked| checked.borrow().get(&campaign_id).cloned())
+}
+
+// Campaigns whose latest check found their balance low, unless they have
+// since been closed or lost their alarm
+pub fn alarms() -> Vec<FaucetBalance> {
+    let low: Vec<_> = CHECKED.with(|checked| {
+        checked
+            .borrow()
+            .values()
+            .filter(|balance| balance.low)
+            .cloned()
+            .collect()
+    });
+    low.into_iter()
+        .filter(|balance| {
+            state::read_campaign(balance.campaign_id, |campaign| {
+                Ok(campaign.low_balance.is_some() && is_monitored(campaign))
+            })
+            .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn is_monitored(campaign: &Campaign) -> bool {
+    campaign.ledger_canister_id.is_some() && campaign.status != CampaignStatus::Closed
+}
+
+// Timers do not survive upgrades, so this runs again in `post_upgrade`
+pub fn start_monitor() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(check_all()));
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(check_all()));
+}
+
+// Checks every campaign with a ledger that can still be claimed from, so
+// anyone can be told what is left to give away
+async fn check_all() {
+    let monitored: Vec<_> = state::CAMPAIGNS
+        .with(|campaigns| campaigns.borrow().list())
+        .into_iter()
+        .filter(is_monitored)
+        .map(|campaign| campaign.id)
+        .collect();
+    for campaign_id in monitored {
+        let _ = check(campaign_id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::LowBalanceAlarm;
+
+    #[test]
+    fn balance_is_reported_in_claims_and_against_the_threshold() {
+        let ledger = Principal::from_slice(&[9]);
+        let campaign = Campaign {
+            amount: 90,
+            ledger_canister_id: Some(ledger),
+            low_balance: Some(LowBalanceAlarm {
+                threshold: 500,
+                pause: false,
+            }),
+            ..Default::default()
+        };
+
+        let checked = FaucetBalance::of(&campaign, ledger, Nat::from(499u64), 10, 7);
+        assert_eq!(checked.per_claim, 100);
+        assert_eq!(checked.claims_covered, 4);
+        assert!(checked.low);
+        assert!(!FaucetBalance::of(&campaign, ledger, Nat::from(500u64), 10, 7).low);
+
+        let unmonitored = Campaign {
+            low_balance: None,
+            ..campaign
+        };
+        assert!(!FaucetBalance::of(&unmonitored, ledger, Nat::from(0u64), 10, 7).low);
+    }
+
+    #[test]
+    fn alarms_clear_once_the_campaign_stops_being_monitored() {
+        let ledger = Principal::from_slice(&[9]);
+        let campaign = Campaign {
+            id: 3,
+            ledger_canister_id: Some(ledger),
+            status: CampaignStatus::Active,
+            low_balance: Some(LowBalanceAlarm {
+                threshold: 500,
+                pause: false,
+            }),
+            ..Default::default()
+        };
+        state::CAMPAIGNS.with(|campaigns| campaigns.borrow_mut().insert(campaign.clone()));
+        let checked = FaucetBalance::of(&campaign, ledger, Nat::from(0u64), 10, 7);
+        CHECKED.with(|checked_balances| checked_balances.borrow_mut().insert(3, checked));
+        assert_eq!(alarms().len(), 1);
+
+        state::mutate_campaign(3, |campaign| {
+            campaign.low_balance = None;
+            Ok(())
+        })
+        .unwrap();
+        assert!(alarms().is_empty());
+    }
+}
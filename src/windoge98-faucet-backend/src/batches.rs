@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap};
+use sha2::{Digest, Sha256};
+
+use crate::types::{CodeBatchInfo, CodeHash, CodeNormalization, FaucetError, Redemption};
+
+pub const MAX_BATCH_SIZE: usize = 1_000;
+
+// Unambiguous when read off a card: no 0/O, 1/I
+const CODE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const GENERATED_CODE_LENGTH: usize = 12;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CodeBatch {
+    pub id: u64,
+    pub campaign_id: u64,
+    // Every code in the batch is hashed with this salt, so a submitted code
+    // is found with one hash per batch
+    pub salt: Vec<u8>,
+    // The campaign's policy when the batch was added; codes are hashed in
+    // normalized form, so later policy changes do not apply to them
+    pub normalization: CodeNormalization,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+// A single-use code in a batch, numbered by its position in the batch
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchCode {
+    pub batch_id: u64,
+    pub serial: u64,
+}
+
+// Batches of single-use codes, plus a (campaign id, batch id) index. Codes
+// are kept only as salted hashes, keyed by (campaign id, hash); redemptions
+// are keyed by (batch id, serial).
+pub struct CodeBatches<M: Memory> {
+    batches: StableBTreeMap<u64, CodeBatch, M>,
+    by_campaign: StableBTreeMap<(u64, u64), (), M>,
+    codes: StableBTreeMap<(u64, [u8; 32]), (u64, u64), M>,
+    redemptions: StableBTreeMap<(u64, u64), (Principal, u64), M>,
+}
+
+impl<M: Memory> CodeBatches<M> {
+    pub fn init(batches: M, by_campaign: M, codes: M, redemptions: M) -> Self {
+        Self {
+            batches: StableBTreeMap::init(batches),
+            by_campaign: StableBTreeMap::init(by_campaign),
+            codes: StableBTreeMap::init(codes),
+            redemptions: StableBTreeMap::init(redemptions),
+        }
+    }
+
+    // Codes already in another batch of the campaign are rejected, so each
+    // one stays redeemable exactly once
+    pub fn add(
+        &mut self,
+        campaign_id: u64,
+        codes: &[String],
+        normalization: CodeNormalization,
+        salt: Vec<u8>,
+        now: u64,
+    ) -> Result<u64, FaucetError> {
+        if codes.is_empty() || codes.len() > MAX_BATCH_SIZE {
+            return Err(FaucetError::InvalidArgument {
+                message: format!("A batch holds 1 to {} codes", MAX_BATCH_SIZE),
+            });
+        }
+        let existing: Vec<_> = self.campaign_batches(campaign_id).collect();
+        let mut hashes = Vec::with_capacity(codes.len());
+        let mut seen = HashSet::with_capacity(codes.len());
+        for code in codes {
+            let code = normalization.apply(code);
+            if self.lookup_in(&existing, campaign_id, &code).is_some() {
+                return Err(FaucetError::InvalidArgument {
+                    message: "Batch contains a code that is already in use".to_string(),
+                });
+            }
+            let hash = key(&CodeHash::new(&code, salt.clone()));
+            if !seen.insert(hash) {
+                return Err(FaucetError::InvalidArgument {
+                    message: "Batch contains duplicate codes".to_string(),
+                });
+            }
+            hashes.push(hash);
+        }
+
+        let id = self.batches.last_key_value().map_or(0, |(id, _)| id + 1);
+        for (serial, hash) in hashes.into_iter().enumerate() {
+            self.codes.insert((campaign_id, hash), (id, serial as u64));
+        }
+        self.by_campaign.insert((campaign_id, id), ());
+        self.batches.insert(
+            id,
+            CodeBatch {
+                id,
+                campaign_id,
+                salt,
+                normalization,
+                size: codes.len() as u64,
+                created_at: now,
+            },
+        );
+        Ok(id)
+    }
+
+    // Indexes batches added before the campaign index existed
+    pub fn index_campaigns(&mut self) {
+        if self.by_campaign.len() == self.batches.len() {
+            return;
+        }
+        for (id, batch) in self.batches.iter() {
+            self.by_campaign.insert((batch.campaign_id, id), ());
+        }
+    }
+
+    fn campaign_batches(&self, campaign_id: u64) -> impl Iterator<Item = CodeBatch> + '_ {
+        self.by_campaign
+            .range((campaign_id, 0)..=(campaign_id, u64::MAX))
+            .map(|((_, id), ())| self.batches.get(&id).expect("Indexed batches exist"))
+    }
+
+    fn lookup(&self, campaign_id: u64, code: &str) -> Option<BatchCode> {
+        let batches: Vec<_> = self.campaign_batches(campaign_id).collect();
+        self.lookup_in(&batches, campaign_id, code)
+    }
+
+    fn lookup_in(&self, batches: &[CodeBatch], campaign_id: u64, code: &str) -> Option<BatchCode> {
+        batches.iter().find_map(|batch| {
+            let hash = key(&CodeHash::new(
+                &batch.normalization.apply(code),
+                batch.salt.clone(),
+            ));
+            self.codes
+                .get(&(campaign_id, hash))
+                .filter(|(batch_id, _)| *batch_id == batch.id)
+                .map(|(batch_id, serial)| BatchCode { batch_id, serial })
+        })
+    }
+
+    // The unredeemed batch code matching `code`
+    pub fn check(&self, campaign_id: u64, code: &str) -> Result<BatchCode, FaucetError> {
+        let batch_code = self
+            .lookup(campaign_id, code)
+            .ok_or(FaucetError::InvalidCode)?;
+        if self
+            .redemptions
+            .contains_key(&(batch_code.batch_id, batch_code.serial))
+        {
+            return Err(FaucetError::CodeAlreadyRedeemed);
+        }
+        Ok(batch_code)
+    }
+
+    pub fn redeem(&mut self, code: BatchCode, principal: Principal, now: u64) {
+        self.redemptions
+            .insert((code.batch_id, code.serial), (principal, now));
+    }
+
+    pub fn unredeem(&mut self, code: BatchCode) {
+        self.redemptions.remove(&(code.batch_id, code.serial));
+    }
+
+    pub fn info(&self, campaign_id: u64) -> Vec<CodeBatchInfo> {
+        self.campaign_batches(campaign_id)
+            .map(|batch| {
+                let redeemed = self.redemptions_in(batch.id).count() as u64;
+                CodeBatchInfo {
+                    id: batch.id,
+                    campaign_id: batch.campaign_id,
+                    size: batch.size,
+                    remaining: batch.size - redeemed,
+                    created_at: batch.created_at,
+                }
+            })
+            .collect()
+    }
+
+    pub fn redemptions(&self, batch_id: u64) -> Vec<Redemption> {
+        self.redemptions_in(batch_id).collect()
+    }
+
+    fn redemptions_in(&self, batch_id: u64) -> impl Iterator<Item = Redemption> + '_ {
+        self.redemptions
+            .range((batch_id, 0)..=(batch_id, u64::MAX))
+            .map(
+                |((batch_id, serial), (principal, redeemed_at))| Redemption {
+                    batch_id,
+                    serial,
+                    principal,
+                    redeemed_at,
+                },
+            )
+    }
+}
+
+fn key(code_hash: &CodeHash) -> [u8; 32] {
+    code_hash
+        .hash
+        .as_slice()
+        .try_into()
+        .expect("SHA-256 is 32 bytes")
+}
+
+// Derives `count` printable codes from `seed`, which should come from
+// `raw_rand`
+pub fn generate_codes(seed: &[u8], count: usize) -> Vec<String> {
+    (0..count as u64)
+        .map(|index| {
+            let mut hasher = Sha256::new();
+            hasher.update(seed);
+            hasher.update(index.to_be_bytes());
+            hasher
+                .finalize()
+                .iter()
+                .take(GENERATED_CODE_LENGTH)
+                .map(|byte| CODE_ALPHABET[(byte % 32) as usize] as char)
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    fn batches() -> CodeBatches<VectorMemory> {
+        CodeBatches::init(
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        )
+    }
+
+    #[test]
+    fn each_code_redeems_once() {
+        let mut batches = batches();
+        let codes = generate_codes(b"seed", 3);
+        let batch_id = batches
+            .add(
+                1,
+                &codes,
+                CodeNormalization::default(),
+                b"salt".to_vec(),
+                10,
+            )
+            .unwrap();
+        let principal = Principal::from_slice(&[1]);
+
+        let code = batches.check(1, &codes[1]).unwrap();
+        assert_eq!(
+            code,
+            BatchCode {
+                batch_id,
+                serial: 1
+            }
+        );
+        assert_eq!(batches.check(2, &codes[1]), Err(FaucetError::InvalidCode));
+        assert_eq!(batches.check(1, "WRONG"), Err(FaucetError::InvalidCode));
+
+        batches.redeem(code, principal, 20);
+        assert_eq!(
+            batches.check(1, &codes[1]),
+            Err(FaucetError::CodeAlreadyRedeemed)
+        );
+        assert_eq!(batches.info(1)[0].remaining, 2);
+        assert_eq!(
+            batches.redemptions(batch_id),
+            vec![Redemption {
+                batch_id,
+                serial: 1,
+                principal,
+                redeemed_at: 20,
+            }]
+        );
+
+        batches.unredeem(code);
+        assert_eq!(batches.check(1, &codes[1]), Ok(code));
+    }
+
+    #[test]
+    fn batches_reject_codes_already_in_use() {
+        let mut batches = batches();
+        let exact = CodeNormalization::default();
+        let lenient = CodeNormalization {
+            trim: true,
+            case_fold: true,
+            nfc: true,
+        };
+        let codes = vec!["CARD-1".to_string()];
+        batches.add(1, &codes, exact, b"first".to_vec(), 0).unwrap();
+
+        assert!(batches
+            .add(1, &codes, exact, b"second".to_vec(), 0)
+            .is_err());
+        assert!(batches
+            .add(
+                1,
+                &["a".to_string(), "A ".to_string()],
+                lenient,
+                b"third".to_vec(),
+                0
+            )
+            .is_err());
+        assert!(batches
+            .add(2, &codes, lenient, b"second".to_vec(), 0)
+            .is_ok());
+        // Each batch is matched under its own policy
+        assert!(batches.check(2, " card-1").is_ok());
+        assert_eq!(batches.check(1, "card-1"), Err(FaucetError::InvalidCode));
+    }
+
+    #[test]
+    fn batches_from_before_the_index_are_found_once_indexed() {
+        let (memory, codes) = (VectorMemory::default(), VectorMemory::default());
+        let mut batches = CodeBatches::init(
+            memory.clone(),
+            VectorMemory::default(),
+            codes.clone(),
+            VectorMemory::default(),
+        );
+        let card = vec!["CARD-1".to_string()];
+        batches
+            .add(1, &card, CodeNormalization::default(), b"salt".to_vec(), 0)
+            .unwrap();
+
+        let mut batches = CodeBatches::init(
+            memory,
+            VectorMemory::default(),
+            codes,
+            VectorMemory::default(),
+        );
+        assert_eq!(batches.check(1, "CARD-1"), Err(FaucetError::InvalidCode));
+        batches.index_campaigns();
+        assert!(batches.check(1, "CARD-1").is_ok());
+        assert!(batches.info(2).is_empty());
+    }
+
+    #[test]
+    fn generated_codes_are_distinct_and_printable() {
+        let codes = generate_codes(b"seed", 100);
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+
+        assert_eq!(unique.len(), 100);
+        assert!(codes.iter().all(|code| code.len() == GENERATED_CODE_LENGTH
+            && code.bytes().all(|byte| CODE_ALPHABET.contains(&byte))));
+    }
+}
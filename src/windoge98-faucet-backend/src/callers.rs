@@ -0,0 +1,116 @@
+use candid::Principal;
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::types::{CallerKind, CallerPolicy, DestinationPolicy, FaucetError};
+
+// Principal classes by their last byte, per the IC interface spec
+const OPAQUE_ID_CLASS: u8 = 0x01;
+const SELF_AUTHENTICATING_CLASS: u8 = 0x02;
+const SELF_AUTHENTICATING_LENGTH: usize = 29;
+
+impl CallerKind {
+    pub fn of(principal: Principal) -> Self {
+        let bytes = principal.as_slice();
+        if principal == Principal::anonymous() {
+            CallerKind::Anonymous
+        } else if bytes.len() == SELF_AUTHENTICATING_LENGTH
+            && bytes.last() == Some(&SELF_AUTHENTICATING_CLASS)
+        {
+            CallerKind::SelfAuthenticating
+        } else if bytes.last() == Some(&OPAQUE_ID_CLASS) {
+            // Opaque ids are only handed out to canisters
+            CallerKind::Canister
+        } else {
+            CallerKind::Other
+        }
+    }
+}
+
+impl CallerPolicy {
+    pub fn check(&self, caller: Principal) -> Result<(), FaucetError> {
+        let kind = CallerKind::of(caller);
+        let allowed = match kind {
+            CallerKind::SelfAuthenticating => true,
+            _ if self.self_authenticating_only => false,
+            CallerKind::Anonymous => self.allow_anonymous,
+            CallerKind::Canister => self.allow_canisters,
+            CallerKind::Other => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(FaucetError::CallerNotAllowed { kind })
+        }
+    }
+}
+
+impl DestinationPolicy {
+    pub fn check(&self, caller: Principal, destination: &Account) -> Result<(), FaucetError> {
+        if *self == DestinationPolicy::CallerAccountsOnly && destination.owner != caller {
+            return Err(FaucetError::DestinationNotAllowed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn principals_are_classified_by_their_class_byte() {
+        let user = Principal::self_authenticating([7u8; 32]);
+        let canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+        assert_eq!(
+            CallerKind::of(Principal::anonymous()),
+            CallerKind::Anonymous
+        );
+        assert_eq!(CallerKind::of(user), CallerKind::SelfAuthenticating);
+        assert_eq!(CallerKind::of(canister), CallerKind::Canister);
+        assert_eq!(
+            CallerKind::of(Principal::management_canister()),
+            CallerKind::Other
+        );
+    }
+
+    #[test]
+    fn policy_denies_anonymous_and_canisters_by_default() {
+        let user = Principal::self_authenticating([7u8; 32]);
+        let canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let mut policy = CallerPolicy::default();
+
+        assert_eq!(
+            policy.check(Principal::anonymous()),
+            Err(FaucetError::CallerNotAllowed {
+                kind: CallerKind::Anonymous
+            })
+        );
+        assert!(policy.check(canister).is_err());
+        assert_eq!(policy.check(user), Ok(()));
+
+        policy.allow_canisters = true;
+        assert_eq!(policy.check(canister), Ok(()));
+        policy.self_authenticating_only = true;
+        assert!(policy.check(canister).is_err());
+        assert_eq!(policy.check(user), Ok(()));
+    }
+
+    #[test]
+    fn destinations_can_be_held_to_the_caller() {
+        let user = Principal::self_authenticating([7u8; 32]);
+        let subaccount = Account {
+            owner: user,
+            subaccount: Some([1; 32]),
+        };
+        let exchange = Account::from(Principal::self_authenticating([8u8; 32]));
+
+        assert_eq!(DestinationPolicy::AnyAccount.check(user, &exchange), Ok(()));
+        let policy = DestinationPolicy::CallerAccountsOnly;
+        assert_eq!(policy.check(user, &subaccount), Ok(()));
+        assert_eq!(
+            policy.check(user, &exchange),
+            Err(FaucetError::DestinationNotAllowed)
+        );
+    }
+}
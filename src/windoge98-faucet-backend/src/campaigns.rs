@@ -0,0 +1,377 @@
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::codes;
+use crate::types::{
+    Campaign, CampaignArgs, CampaignInfo, CampaignStatus, ClaimMode, CodeEntry, CodeNormalization,
+    FaucetError, TokenPayout,
+};
+
+// The faucet's original settings live on as campaign 0, and the endpoints
+// that predate campaigns act on it
+pub const DEFAULT_CAMPAIGN_ID: u64 = 0;
+pub const DEFAULT_CAMPAIGN_NAME: &str = "Default";
+// Each is a transfer of its own per claim
+pub const MAX_EXTRA_PAYOUTS: usize = 4;
+
+// Every campaign ever created, keyed by id. Closed campaigns are kept so
+// their claims can still be attributed.
+pub struct Campaigns<M: Memory> {
+    campaigns: StableBTreeMap<u64, Campaign, M>,
+}
+
+impl<M: Memory> Campaigns<M> {
+    pub fn init(memory: M) -> Self {
+        Self {
+            campaigns: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.campaigns
+            .last_key_value()
+            .map_or(DEFAULT_CAMPAIGN_ID, |(id, _)| id + 1)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Campaign> {
+        self.campaigns.get(&id)
+    }
+
+    pub fn insert(&mut self, campaign: Campaign) {
+        self.campaigns.insert(campaign.id, campaign);
+    }
+
+    pub fn list(&self) -> Vec<Campaign> {
+        self.campaigns
+            .iter()
+            .map(|(_, campaign)| campaign)
+            .collect()
+    }
+
+    // Applies `f` to a copy of the campaign and only writes it back if `f`
+    // succeeds
+    pub fn update<R>(
+        &mut self,
+        id: u64,
+        f: impl FnOnce(&mut Campaign) -> Result<R, FaucetError>,
+    ) -> Result<R, FaucetError> {
+        let mut campaign = self.get(id).ok_or(FaucetError::CampaignNotFound)?;
+        let result = f(&mut campaign)?;
+        self.insert(campaign);
+        Ok(result)
+    }
+}
+
+impl Campaign {
+    // `now` salts the code if it is given in plaintext
+    pub fn new(id: u64, args: CampaignArgs, now: u64) -> Self {
+        let mut campaign = Self {
+            id,
+            status: CampaignStatus::Active,
+            ..Default::default()
+        };
+        campaign.apply(args, now);
+        campaign
+    }
+
+    // Replaces the custodian-editable settings, keeping status and totals
+    pub fn apply(&mut self, args: CampaignArgs, now: u64) {
+        self.name = args.name;
+        self.set_codes(args.codes, args.normalization, now);
+        self.amount = args.amount;
+        self.ledger_canister_id = Some(args.ledger_canister_id);
+        self.ledger_kind = args.ledger_kind;
+        self.transfer_fee = args.transfer_fee;
+        self.claim_mode = args.claim_mode;
+        self.budget = args.budget;
+        self.max_claims = args.max_claims;
+        self.start_time = args.start_time;
+        self.end_time = args.end_time;
+        self.low_balance = args.low_balance;
+        self.extra_payouts = args.extra_payouts;
+    }
+
+    // Replaces every active code; the policy is set along with them since
+    // plaintext codes are hashed in their normalized form
+    pub fn set_codes(
+        &mut self,
+        entries: Vec<CodeEntry>,
+        normalization: CodeNormalization,
+        now: u64,
+    ) {
+        self.codes = codes::store_entries(entries, normalization, codes::salt(self.id, now));
+        self.normalization = normalization;
+    }
+
+    // Returns the label of the matching code. `now` picks the current step
+    // of a rotating code.
+    pub fn check_code(&self, code: &str, now: u64) -> Result<String, FaucetError> {
+        let code = self.normalization.apply(code);
+        self.codes
+            .iter()
+            .find(|stored| stored.code.matches(&code, now))
+            .map(|stored| stored.label.clone())
+            .ok_or(FaucetError::InvalidCode)
+    }
+
+    // Whether the campaign accepts claims at `now`
+    pub fn check_open(&self, now: u64) -> Result<(), FaucetError> {
+        match self.status {
+            CampaignStatus::Active => {}
+            CampaignStatus::Paused => return Err(FaucetError::Disabled),
+            CampaignStatus::Closed => return Err(FaucetError::CampaignClosed),
+        }
+        if let Some(start_time) = self.start_time.filter(|start| now < *start) {
+            return Err(FaucetError::CampaignNotStarted { start_time });
+        }
+        if self.end_time.is_some_and(|end| now >= end) {
+            return Err(FaucetError::CampaignEnded);
+        }
+        Ok(())
+    }
+
+    // Holds budget and a claim slot for one claim of `amount`, counting
+    // claims that are still in flight as already paid
+    pub fn reserve(&mut self) -> Result<(), FaucetError> {
+        let committed = self.disbursed.saturating_add(self.reserved_amount);
+        if self
+            .budget
+            .is_some_and(|budget| committed.saturating_add(self.amount) > budget)
+        {
+            return Err(FaucetError::BudgetExhausted);
+        }
+        let claims = self.claim_count.saturating_add(self.reserved_claims);
+        if self
+            .max_claims
+            .is_some_and(|max_claims| claims >= max_claims)
+        {
+            return Err(FaucetError::ClaimLimitReached);
+        }
+        self.reserved_amount += self.amount;
+        self.reserved_claims += 1;
+        Ok(())
+    }
+
+    pub fn release(&mut self, amount: u64) {
+        self.reserved_amount = self.reserved_amount.saturating_sub(amount);
+        self.reserved_claims = self.reserved_claims.saturating_sub(1);
+    }
+
+    // Turns a reservation into a payout, pausing the campaign once it cannot
+    // afford another claim or has hit its claim limit
+    pub fn settle(&mut self, amount: u64) {
+        self.release(amount);
+        self.disbursed = self.disbursed.saturating_add(amount);
+        self.claim_count += 1;
+        let out_of_budget = self
+            .budget
+            .is_some_and(|budget| self.disbursed.saturating_add(self.amount) > budget);
+        let out_of_claims = self
+            .max_claims
+            .is_some_and(|max_claims| self.claim_count >= max_claims);
+        if (out_of_budget || out_of_claims) && self.status == CampaignStatus::Active {
+            self.status = CampaignStatus::Paused;
+        }
+    }
+
+    pub fn require_not_closed(&self) -> Result<(), FaucetError> {
+        if self.status == CampaignStatus::Closed {
+            return Err(FaucetError::CampaignClosed);
+        }
+        Ok(())
+    }
+}
+
+impl From<&Campaign> for CampaignInfo {
+    fn from(campaign: &Campaign) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name.clone(),
+            normalization: campaign.normalization,
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            low_balance: campaign.low_balance,
+            extra_payouts: campaign.extra_payouts.clone(),
+        }
+    }
+}
+
+pub fn validate_claim_mode(claim_mode: ClaimMode) -> Result<(), FaucetError> {
+    if claim_mode == (ClaimMode::Cooldown { seconds: 0 }) {
+        return Err(FaucetError::InvalidArgument {
+            message: "Cooldown must be at least one second".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn validate(args: &CampaignArgs) -> Result<(), FaucetError> {
+    validate_claim_mode(args.claim_mode)?;
+    codes::validate_entries(&args.codes)?;
+    if args.name.trim().is_empty() {
+        return Err(FaucetError::InvalidArgument {
+            message: "Campaign name cannot be empty".to_string(),
+        });
+    }
+    validate_extra_payouts(&args.extra_payouts)?;
+    if let (Some(start), Some(end)) = (args.start_time, args.end_time) {
+        if start >= end {
+            return Err(FaucetError::InvalidArgument {
+                message: "Campaign must start before it ends".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_extra_payouts(payouts: &[TokenPayout]) -> Result<(), FaucetError> {
+    if payouts.len() > MAX_EXTRA_PAYOUTS {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("At most {} extra payouts per claim", MAX_EXTRA_PAYOUTS),
+        });
+    }
+    if payouts.iter().any(|payout| payout.amount == 0) {
+        return Err(FaucetError::InvalidArgument {
+            message: "Extra payouts must be of a positive amount".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CodeInput;
+    use candid::Principal;
+    use ic_stable_structures::VectorMemory;
+
+    fn args() -> CampaignArgs {
+        CampaignArgs {
+            name: "Space".to_string(),
+            codes: vec![
+                CodeEntry {
+                    label: "partner-a".to_string(),
+                    code: CodeInput::Plaintext("Windoge".to_string()),
+                },
+                CodeEntry {
+                    label: "partner-b".to_string(),
+                    code: CodeInput::Plaintext("Space".to_string()),
+                },
+            ],
+            normalization: CodeNormalization {
+                trim: true,
+                case_fold: true,
+                nfc: false,
+            },
+            amount: 100,
+            ledger_canister_id: Principal::from_slice(&[9]),
+            ledger_kind: Default::default(),
+            transfer_fee: None,
+            claim_mode: ClaimMode::OneShot,
+            budget: Some(250),
+            max_claims: None,
+            start_time: Some(1_000),
+            end_time: Some(2_000),
+            low_balance: None,
+            extra_payouts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn campaign_is_open_within_its_window() {
+        let mut campaign = Campaign::new(1, args(), 0);
+
+        assert_eq!(
+            campaign.check_open(999),
+            Err(FaucetError::CampaignNotStarted { start_time: 1_000 })
+        );
+        assert_eq!(campaign.check_open(1_000), Ok(()));
+        assert_eq!(campaign.check_open(2_000), Err(FaucetError::CampaignEnded));
+
+        campaign.status = CampaignStatus::Paused;
+        assert_eq!(campaign.check_open(1_500), Err(FaucetError::Disabled));
+    }
+
+    #[test]
+    fn any_active_code_is_accepted_and_attributed() {
+        let campaign = Campaign::new(1, args(), 0);
+
+        assert_eq!(
+            campaign.check_code(" windoge", 0),
+            Ok("partner-a".to_string())
+        );
+        assert_eq!(
+            campaign.check_code("SPACE ", 0),
+            Ok("partner-b".to_string())
+        );
+        assert_eq!(
+            campaign.check_code("moon", 0),
+            Err(FaucetError::InvalidCode)
+        );
+        assert_eq!(
+            Campaign::default().check_code("", 0),
+            Err(FaucetError::InvalidCode)
+        );
+    }
+
+    #[test]
+    fn reservations_count_against_the_budget() {
+        let mut campaign = Campaign::new(1, args(), 0);
+
+        assert_eq!(campaign.reserve(), Ok(()));
+        assert_eq!(campaign.reserve(), Ok(()));
+        // 200 is in flight, so a third claim of 100 would overspend 250
+        assert_eq!(campaign.reserve(), Err(FaucetError::BudgetExhausted));
+
+        campaign.release(100);
+        campaign.settle(100);
+        assert_eq!(campaign.disbursed, 100);
+        assert_eq!(campaign.reserved_amount, 0);
+        assert_eq!(campaign.status, CampaignStatus::Active);
+        assert_eq!(campaign.reserve(), Ok(()));
+        campaign.settle(100);
+        // 50 left cannot cover another claim
+        assert_eq!(campaign.status, CampaignStatus::Paused);
+    }
+
+    #[test]
+    fn claim_limit_pauses_the_campaign() {
+        let mut campaign = Campaign::new(1, args(), 0);
+        campaign.budget = None;
+        campaign.max_claims = Some(1);
+
+        assert_eq!(campaign.reserve(), Ok(()));
+        assert_eq!(campaign.reserve(), Err(FaucetError::ClaimLimitReached));
+        campaign.settle(100);
+
+        assert_eq!(campaign.claim_count, 1);
+        assert_eq!(campaign.status, CampaignStatus::Paused);
+    }
+
+    #[test]
+    fn failed_update_leaves_campaign_untouched() {
+        let mut campaigns = Campaigns::init(VectorMemory::default());
+        campaigns.insert(Campaign::new(campaigns.next_id(), args(), 0));
+
+        let result = campaigns.update(0, |campaign| {
+            campaign.amount = 1;
+            campaign.status = CampaignStatus::Closed;
+            Err::<(), _>(FaucetError::BudgetExhausted)
+        });
+
+        assert_eq!(result, Err(FaucetError::BudgetExhausted));
+        assert_eq!(campaigns.get(0).unwrap().amount, 100);
+        assert_eq!(
+            campaigns.update(7, |_| Ok(())),
+            Err(FaucetError::CampaignNotFound)
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use candid::Principal;
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::types::{ClaimPage, ClaimQuery, ClaimRecord, MAX_PAGE_SIZE};
+
+// Upper bound on records a single query visits, so a sparse filter returns
+// a cursor instead of running out of instructions
+const MAX_SCANNED_PER_QUERY: usize = 10_000;
+
+// Every claim ever paid, keyed by claim id, plus a (principal, id) index for
+// per-principal lookups. Ids are assigned in insertion order, so record
+// timestamps never decrease with the id.
+pub struct ClaimLog<M: Memory> {
+    records: StableBTreeMap<u64, ClaimRecord, M>,
+    by_principal: StableBTreeMap<(Principal, u64), (), M>,
+}
+
+impl<M: Memory> ClaimLog<M> {
+    pub fn init(records: M, by_principal: M) -> Self {
+        Self {
+            records: StableBTreeMap::init(records),
+            by_principal: StableBTreeMap::init(by_principal),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.records.last_key_value().map_or(0, |(id, _)| id + 1)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.records.len()
+    }
+
+    pub fn insert(&mut self, record: ClaimRecord) {
+        self.by_principal.insert((record.principal, record.id), ());
+        self.records.insert(record.id, record);
+    }
+
+    // Skips ids that are not in the log
+    pub fn get_many(&self, ids: impl IntoIterator<Item = u64>) -> Vec<ClaimRecord> {
+        ids.into_iter()
+            .filter_map(|id| self.records.get(&id))
+            .collect()
+    }
+
+    pub fn last_for(&self, principal: Principal) -> Option<ClaimRecord> {
+        self.by_principal
+            .range((principal, 0)..=(principal, u64::MAX))
+            .next_back()
+            .and_then(|((_, id), _)| self.records.get(&id))
+    }
+
+    pub fn for_principal(&self, principal: Principal) -> Vec<ClaimRecord> {
+        self.by_principal
+            .range((principal, 0)..=(principal, u64::MAX))
+            .filter_map(|((_, id), _)| self.records.get(&id))
+            .collect()
+    }
+
+    // Oldest first, starting at `query.cursor`
+    pub fn query(&self, query: &ClaimQuery) -> ClaimPage {
+        let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize;
+        let start = query.cursor.unwrap_or(0);
+        let ids: Box<dyn Iterator<Item = u64> + '_> = match query.principal {
+            Some(principal) => Box::new(
+                self.by_principal
+                    .range((principal, start)..=(principal, u64::MAX))
+                    .map(|((_, id), _)| id),
+            ),
+            None => Box::new(self.records.keys_range(start..)),
+        };
+
+        let mut claims = Vec::new();
+        let mut next_cursor = None;
+        for (scanned, id) in ids.enumerate() {
+            if claims.len() == limit || scanned == MAX_SCANNED_PER_QUERY {
+                next_cursor = Some(id);
+                break;
+            }
+            let Some(record) = self.records.get(&id) else {
+                continue;
+            };
+            if query.to_time.is_some_and(|to| record.timestamp > to) {
+                break;
+            }
+            if query.from_time.is_none_or(|from| record.timestamp >= from)
+                && query.campaign_id.is_none_or(|id| record.campaign_id == id)
+                && query
+                    .code_label
+                    .as_ref()
+                    .is_none_or(|label| record.code_label.as_ref() == Some(label))
+            {
+                claims.push(record);
+            }
+        }
+
+        ClaimPage {
+            claims,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ClaimStatus;
+    use ic_stable_structures::VectorMemory;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn log_with_claims() -> ClaimLog<VectorMemory> {
+        let mut log = ClaimLog::init(VectorMemory::default(), VectorMemory::default());
+        for id in 0..10 {
+            log.insert(ClaimRecord {
+                id,
+                campaign_id: id % 3,
+                principal: principal((id % 2) as u8),
+                destination: principal((id % 2) as u8).into(),
+                amount: 100,
+                fee: None,
+                timestamp: id * 10,
+                ledger: None,
+                block_index: None,
+                status: ClaimStatus::Completed,
+                code_label: (id % 4 == 0).then(|| "partner-a".to_string()),
+            });
+        }
+        log
+    }
+
+    fn ids(page: &ClaimPage) -> Vec<u64> {
+        page.claims.iter().map(|claim| claim.id).collect()
+    }
+
+    #[test]
+    fn query_pages_with_cursor() {
+        let log = log_with_claims();
+        let mut query = ClaimQuery {
+            limit: Some(4),
+            ..Default::default()
+        };
+
+        let first = log.query(&query);
+        assert_eq!(ids(&first), vec![0, 1, 2, 3]);
+        assert_eq!(first.next_cursor, Some(4));
+
+        query.cursor = Some(8);
+        let last = log.query(&query);
+        assert_eq!(ids(&last), vec![8, 9]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn query_filters_by_principal_time_campaign_and_code() {
+        let log = log_with_claims();
+        let query = ClaimQuery {
+            principal: Some(principal(1)),
+            from_time: Some(20),
+            to_time: Some(70),
+            ..Default::default()
+        };
+
+        assert_eq!(ids(&log.query(&query)), vec![3, 5, 7]);
+        let query = ClaimQuery {
+            campaign_id: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(ids(&log.query(&query)), vec![1, 4, 7]);
+        let query = ClaimQuery {
+            code_label: Some("partner-a".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&log.query(&query)), vec![0, 4, 8]);
+        assert_eq!(log.for_principal(principal(0)).len(), 5);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::types::{
+    CampaignCode, CodeEntry, CodeHash, CodeInput, CodeNormalization, FaucetError, LabeledCode,
+    RotatingCode, NANOS_PER_SECOND,
+};
+
+// Each active code costs a hash per claim, so the set is kept small
+pub const MAX_CAMPAIGN_CODES: usize = 100;
+// The code set by the single-code endpoints that predate labels
+pub const DEFAULT_CODE_LABEL: &str = "default";
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+const SHA256_BLOCK_LENGTH: usize = 64;
+const MIN_SECRET_LENGTH: usize = 16;
+const ROTATING_CODE_DIGITS: u32 = 6;
+
+impl CodeHash {
+    pub fn new(code: &str, salt: Vec<u8>) -> Self {
+        let hash = digest(&salt, code);
+        Self { salt, hash }
+    }
+
+    pub fn matches(&self, code: &str) -> bool {
+        digest(&self.salt, code) == self.hash
+    }
+}
+
+impl RotatingCode {
+    // The code for `now` or the step either side of it, to allow for the
+    // delay between a code being shown and submitted
+    pub fn matches(&self, code: &str, now: u64) -> bool {
+        let counter = now / NANOS_PER_SECOND / self.step_seconds;
+        [
+            counter.saturating_sub(1),
+            counter,
+            counter.saturating_add(1),
+        ]
+        .iter()
+        .any(|counter| self.code_at(*counter) == code)
+    }
+
+    // RFC 6238 TOTP with HMAC-SHA-256, so authenticator tools can show it
+    fn code_at(&self, counter: u64) -> String {
+        let mac = hmac_sha256(&self.secret, &counter.to_be_bytes());
+        let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+        let truncated = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            truncated % 10u32.pow(ROTATING_CODE_DIGITS),
+            width = ROTATING_CODE_DIGITS as usize
+        )
+    }
+}
+
+impl CampaignCode {
+    pub fn matches(&self, code: &str, now: u64) -> bool {
+        match self {
+            CampaignCode::Hashed(code_hash) => code_hash.matches(code),
+            CampaignCode::Rotating(rotating) => rotating.matches(code, now),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; SHA256_BLOCK_LENGTH];
+    if key.len() > SHA256_BLOCK_LENGTH {
+        block[..HASH_LENGTH].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn digest(salt: &[u8], code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+impl CodeNormalization {
+    pub fn apply(&self, code: &str) -> String {
+        let code = if self.trim { code.trim() } else { code };
+        let code: String = if self.nfc {
+            code.nfc().collect()
+        } else {
+            code.to_string()
+        };
+        if self.case_fold {
+            code.to_lowercase()
+        } else {
+            code
+        }
+    }
+}
+
+// Salts only need to be unique, not secret, so they are derived from the
+// campaign and the time the code was set
+pub fn salt(campaign_id: u64, entropy: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"windoge98-faucet code salt");
+    hasher.update(campaign_id.to_be_bytes());
+    hasher.update(entropy.to_be_bytes());
+    hasher.finalize()[..SALT_LENGTH].to_vec()
+}
+
+pub fn validate(input: &CodeInput) -> Result<(), FaucetError> {
+    match input {
+        CodeInput::Plaintext(_) => Ok(()),
+        CodeInput::Hashed(code_hash) if code_hash.salt.is_empty() => {
+            Err(FaucetError::InvalidArgument {
+                message: "Code hash needs a salt".to_string(),
+            })
+        }
+        CodeInput::Hashed(code_hash) if code_hash.hash.len() != HASH_LENGTH => {
+            Err(FaucetError::InvalidArgument {
+                message: format!("Code hash must be {} bytes of SHA-256", HASH_LENGTH),
+            })
+        }
+        CodeInput::Hashed(_) => Ok(()),
+        CodeInput::Rotating(rotating) if rotating.secret.len() < MIN_SECRET_LENGTH => {
+            Err(FaucetError::InvalidArgument {
+                message: format!(
+                    "Rotating code secret must be at least {} bytes",
+                    MIN_SECRET_LENGTH
+                ),
+            })
+        }
+        CodeInput::Rotating(rotating) if rotating.step_seconds == 0 => {
+            Err(FaucetError::InvalidArgument {
+                message: "Rotating code step must be at least one second".to_string(),
+            })
+        }
+        CodeInput::Rotating(_) => Ok(()),
+    }
+}
+
+// Labels must be unique so claims can be attributed to a single code
+pub fn validate_entries(entries: &[CodeEntry]) -> Result<(), FaucetError> {
+    if entries.len() > MAX_CAMPAIGN_CODES {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("A campaign holds at most {} codes", MAX_CAMPAIGN_CODES),
+        });
+    }
+    let mut labels = HashSet::with_capacity(entries.len());
+    for entry in entries {
+        if entry.label.trim().is_empty() {
+            return Err(FaucetError::InvalidArgument {
+                message: "Code label cannot be empty".to_string(),
+            });
+        }
+        if !labels.insert(entry.label.as_str()) {
+            return Err(FaucetError::InvalidArgument {
+                message: format!("Duplicate code label {}", entry.label),
+            });
+        }
+        validate(&entry.code)?;
+    }
+    Ok(())
+}
+
+// Plaintext codes are normalized and hashed with `salt`; precomputed hashes
+// keep their own and rotating codes keep their secret
+pub fn store(input: CodeInput, normalization: CodeNormalization, salt: Vec<u8>) -> CampaignCode {
+    match input {
+        CodeInput::Plaintext(code) => {
+            CampaignCode::Hashed(CodeHash::new(&normalization.apply(&code), salt))
+        }
+        CodeInput::Hashed(code_hash) => CampaignCode::Hashed(code_hash),
+        CodeInput::Rotating(rotating) => CampaignCode::Rotating(rotating),
+    }
+}
+
+pub fn store_entries(
+    entries: Vec<CodeEntry>,
+    normalization: CodeNormalization,
+    salt: Vec<u8>,
+) -> Vec<LabeledCode> {
+    entries
+        .into_iter()
+        .map(|entry| LabeledCode {
+            label: entry.label,
+            code: store(entry.code, normalization, salt.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precomputed_hash_matches_plaintext() {
+        let salt = b"event-salt".to_vec();
+        let mut precomputed = Sha256::new();
+        precomputed.update(&salt);
+        precomputed.update(b"windoge");
+        let input = CodeInput::Hashed(CodeHash {
+            salt: salt.clone(),
+            hash: precomputed.finalize().to_vec(),
+        });
+
+        assert_eq!(validate(&input), Ok(()));
+        let code = store(input, CodeNormalization::default(), Vec::new());
+        assert!(code.matches("windoge", 0));
+        assert!(!code.matches("windoge ", 0));
+        assert_eq!(code, CampaignCode::Hashed(CodeHash::new("windoge", salt)));
+    }
+
+    #[test]
+    fn plaintext_is_not_stored() {
+        let code = store(
+            CodeInput::Plaintext("windoge".to_string()),
+            CodeNormalization::default(),
+            salt(0, 1),
+        );
+
+        assert!(code.matches("windoge", 0));
+        let CampaignCode::Hashed(code_hash) = code else {
+            panic!("plaintext codes are stored hashed");
+        };
+        assert_ne!(code_hash.hash, b"windoge".to_vec());
+        assert_ne!(salt(0, 1), salt(1, 1));
+        assert_ne!(salt(0, 1), salt(0, 2));
+    }
+
+    // RFC 6238 appendix B, SHA-256 column, last six digits
+    #[test]
+    fn rotating_code_matches_rfc_6238() {
+        let rotating = RotatingCode {
+            secret: b"12345678901234567890123456789012".to_vec(),
+            step_seconds: 30,
+        };
+        let at = |seconds: u64| seconds * NANOS_PER_SECOND;
+
+        assert_eq!(rotating.code_at(59 / 30), "119246");
+        assert_eq!(rotating.code_at(1_111_111_109 / 30), "084774");
+        assert!(rotating.matches("084774", at(1_111_111_109)));
+        // One step of skew either way is accepted, two is not
+        assert!(rotating.matches("084774", at(1_111_111_109 + 30)));
+        assert!(rotating.matches("084774", at(1_111_111_109 - 30)));
+        assert!(!rotating.matches("084774", at(1_111_111_109 + 60)));
+    }
+
+    #[test]
+    fn rotating_code_needs_a_secret_and_step() {
+        let input = |secret: &[u8], step_seconds| {
+            CodeInput::Rotating(RotatingCode {
+                secret: secret.to_vec(),
+                step_seconds,
+            })
+        };
+
+        assert!(validate(&input(b"short", 30)).is_err());
+        assert!(validate(&input(&[7; 20], 0)).is_err());
+        assert_eq!(validate(&input(&[7; 20], 30)), Ok(()));
+    }
+
+    #[test]
+    fn normalization_forgives_spacing_case_and_composition() {
+        let lenient = CodeNormalization {
+            trim: true,
+            case_fold: true,
+            nfc: true,
+        };
+        // "Café" with a precomposed é, submitted with e + combining acute
+        let code = store(
+            CodeInput::Plaintext("Café".to_string()),
+            lenient,
+            salt(0, 1),
+        );
+
+        assert!(code.matches(&lenient.apply(" CAFE\u{301}\n"), 0));
+        assert!(!code.matches(&lenient.apply("cafe"), 0));
+
+        let exact = CodeNormalization::default();
+        assert_eq!(exact.apply(" Café "), " Café ");
+        assert_ne!(exact.apply("Cafe\u{301}"), exact.apply("Café"));
+    }
+
+    #[test]
+    fn code_labels_must_be_unique() {
+        let entry = |label: &str| CodeEntry {
+            label: label.to_string(),
+            code: CodeInput::Plaintext("windoge".to_string()),
+        };
+
+        assert_eq!(
+            validate_entries(&[entry("partner-a"), entry("partner-b")]),
+            Ok(())
+        );
+        assert!(validate_entries(&[entry("partner-a"), entry("partner-a")]).is_err());
+        assert!(validate_entries(&[entry(" ")]).is_err());
+    }
+}
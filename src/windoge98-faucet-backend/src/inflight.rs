@@ -0,0 +1,60 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::thread::LocalKey;
+
+use candid::Principal;
+
+use crate::types::FaucetError;
+
+thread_local! {
+    // Heap only: a canister is stopped before an upgrade, so nothing is in
+    // flight across one
+    static CLAIMS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static TRANSFERS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Marks `key` as in flight until dropped, including when ic-cdk drops the
+// future holding it after a trap
+pub struct InFlight<K: Ord + Copy + 'static> {
+    set: &'static LocalKey<RefCell<BTreeSet<K>>>,
+    key: K,
+}
+
+impl<K: Ord + Copy + 'static> InFlight<K> {
+    // `None` if `key` is already in flight
+    fn acquire_in(set: &'static LocalKey<RefCell<BTreeSet<K>>>, key: K) -> Option<Self> {
+        set.with(|in_flight| in_flight.borrow_mut().insert(key))
+            .then_some(Self { set, key })
+    }
+
+    pub fn key(&self) -> K {
+        self.key
+    }
+}
+
+impl<K: Ord + Copy + 'static> Drop for InFlight<K> {
+    fn drop(&mut self) {
+        self.set
+            .with(|in_flight| in_flight.borrow_mut().remove(&self.key));
+    }
+}
+
+// A principal's claim, so a second claim from it cannot pass the claimed
+// check while the first awaits the ledger
+pub type ClaimGuard = InFlight<Principal>;
+
+impl ClaimGuard {
+    pub fn acquire(principal: Principal) -> Result<Self, FaucetError> {
+        Self::acquire_in(&CLAIMS, principal).ok_or(FaucetError::ClaimInProgress)
+    }
+}
+
+// A journal entry's transfer call, keeping the reconciler and custodians off
+// the entry meanwhile
+pub type AttemptGuard = InFlight<u64>;
+
+impl AttemptGuard {
+    pub fn acquire(id: u64) -> Result<Self, FaucetError> {
+        Self::acquire_in(&TRANSFERS, id).ok_or(FaucetError::TransferInFlight { journal_id: id })
+    }
+}
@@ -0,0 +1,521 @@
+use std::time::Duration;
+
+use candid::{Nat, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::inflight::AttemptGuard;
+use crate::ledger::{self, Payout, TransferFailure};
+use crate::state::{self, CLAIMED_PRINCIPALS, CLAIMS, CODE_BATCHES, JOURNAL, RECENT_CLAIMS};
+use crate::types::{
+    ClaimRecord, ClaimStatus, FaucetError, JournalEntry, PayoutReceipt, TransferStatus,
+    NANOS_PER_SECOND,
+};
+
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+// Entries retried per reconciler run, so one run stays within its limits
+const MAX_RETRIES_PER_RUN: usize = 20;
+// Ledgers only deduplicate transfers created within the last 24 hours. Past
+// that a retry cannot tell whether the original was paid, so the entry is
+// left to custodians.
+const DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * NANOS_PER_SECOND;
+// A transfer still pending after this long is reported as stuck
+const STUCK_AFTER_NANOS: u64 = 10 * 60 * NANOS_PER_SECOND;
+
+// Every payout attempted, keyed by entry id, plus a (principal, id) index of
+// the unresolved ones: pending, or owed
+pub struct Journal<M: Memory> {
+    entries: StableBTreeMap<u64, JournalEntry, M>,
+    pending: StableBTreeMap<(Principal, u64), (), M>,
+}
+
+impl<M: Memory> Journal<M> {
+    pub fn init(entries: M, pending: M) -> Self {
+        Self {
+            entries: StableBTreeMap::init(entries),
+            pending: StableBTreeMap::init(pending),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.entries.last_key_value().map_or(0, |(id, _)| id + 1)
+    }
+
+    pub fn insert(&mut self, entry: JournalEntry) {
+        if entry.status == TransferStatus::Pending {
+            self.pending.insert((entry.principal, entry.id), ());
+        }
+        self.entries.insert(entry.id, entry);
+    }
+
+    pub fn get(&self, id: u64) -> Option<JournalEntry> {
+        self.entries.get(&id)
+    }
+
+    // Only a pending or owed entry can be resolved, and only once
+    pub fn resolve(&mut self, id: u64, status: TransferStatus, now: u64) -> Option<JournalEntry> {
+        let mut entry = self.get(id).filter(is_unresolved)?;
+        self.pending.remove(&(entry.principal, id));
+        entry.status = status;
+        entry.updated_at = now;
+        self.entries.insert(id, entry.clone());
+        Some(entry)
+    }
+
+    // Marks a pending extra payout the ledger refused as owed
+    pub fn owe(&mut self, id: u64, message: String, now: u64) -> Option<JournalEntry> {
+        let mut entry = self
+            .get(id)
+            .filter(|entry| entry.status == TransferStatus::Pending)?;
+        entry.status = TransferStatus::Owed { message };
+        entry.updated_at = now;
+        self.entries.insert(id, entry.clone());
+        Some(entry)
+    }
+
+    // Makes an owed payout pending again. The refused transfer was never
+    // applied, so the retry is sent as a new one.
+    pub fn reopen(&mut self, id: u64, now: u64) -> Option<JournalEntry> {
+        let mut entry = self
+            .get(id)
+            .filter(|entry| matches!(entry.status, TransferStatus::Owed { .. }))?;
+        entry.status = TransferStatus::Pending;
+        entry.created_at_time = now;
+        entry.updated_at = now;
+        self.entries.insert(id, entry.clone());
+        Some(entry)
+    }
+
+    pub fn record_attempt(&mut self, id: u64, error: &FaucetError, now: u64) {
+        if let Some(mut entry) = self.get(id) {
+            entry.attempts += 1;
+            entry.last_error = Some(format!("{:?}", error));
+            entry.updated_at = now;
+            self.entries.insert(id, entry);
+        }
+    }
+
+    // The principal's pending main payout from `campaign_id`, if any. Extra
+    // payouts are left out: their claim has gone through already.
+    pub fn pending_for(&self, principal: Principal, campaign_id: u64) -> Option<u64> {
+        self.pending
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|((_, id), _)| id)
+            .find(|id| {
+                self.get(*id).is_some_and(|entry| {
+                    entry.campaign_id == campaign_id
+                        && entry.main_payout.is_none()
+                        && entry.status == TransferStatus::Pending
+                })
+            })
+    }
+
+    // The unresolved extra payouts of main payout `id`
+    pub fn extra_payouts_of(&self, principal: Principal, id: u64) -> Vec<JournalEntry> {
+        self.pending
+            .range((principal, id)..=(principal, u64::MAX))
+            .filter_map(|((_, extra_id), _)| self.get(extra_id))
+            .filter(|entry| entry.main_payout == Some(id))
+            .collect()
+    }
+
+    // Oldest first
+    pub fn pending(&self) -> Vec<JournalEntry> {
+        self.unresolved()
+            .filter(|entry| entry.status == TransferStatus::Pending)
+            .collect()
+    }
+
+    // Oldest first
+    pub fn owed(&self) -> Vec<JournalEntry> {
+        self.unresolved()
+            .filter(|entry| matches!(entry.status, TransferStatus::Owed { .. }))
+            .collect()
+    }
+
+    fn unresolved(&self) -> impl Iterator<Item = JournalEntry> {
+        let mut entries: Vec<_> = self
+            .pending
+            .iter()
+            .filter_map(|((_, id), _)| self.get(id))
+            .collect();
+        entries.sort_by_key(|entry| entry.id);
+        entries.into_iter()
+    }
+
+    pub fn stuck(&self, now: u64) -> Vec<JournalEntry> {
+        self.pending()
+            .into_iter()
+            .filter(|entry| now.saturating_sub(entry.created_at_time) >= STUCK_AFTER_NANOS)
+            .collect()
+    }
+}
+
+fn is_unresolved(entry: &JournalEntry) -> bool {
+    matches!(
+        entry.status,
+        TransferStatus::Pending | TransferStatus::Owed { .. }
+    )
+}
+
+impl JournalEntry {
+    fn payout(&self) -> Payout {
+        Payout {
+            to: self.destination,
+            amount: self.amount,
+            fee: self.fee,
+            memo: self.id,
+            created_at_time: self.created_at_time,
+        }
+    }
+
+    fn receipt(&self) -> PayoutReceipt {
+        PayoutReceipt {
+            journal_id: self.id,
+            ledger: self.ledger,
+            amount: self.amount,
+            status: self.status.clone(),
+        }
+    }
+}
+
+// Sends, or resends, the guarded entry's transfer and resolves the entry if
+// the ledger gave a definite answer. Returns the claim id and block index.
+pub async fn attempt(guard: &AttemptGuard) -> Result<(u64, Nat), FaucetError> {
+    // Read under the guard: a copy taken earlier may since have been
+    // resolved, and resending a refused transfer would pay it
+    let entry = JOURNAL
+        .with(|journal| journal.borrow().get(guard.key()))
+        .ok_or_else(|| FaucetError::InvalidArgument {
+            message: format!("No journal entry with id {}", guard.key()),
+        })?;
+    match entry.status {
+        TransferStatus::Pending => {}
+        TransferStatus::Succeeded {
+            block_index,
+            claim_id,
+        } => return Ok((claim_id, block_index)),
+        TransferStatus::Failed { message } | TransferStatus::Owed { message } => {
+            return Err(FaucetError::LedgerError { message })
+        }
+    }
+    let result = ledger::transfer(entry.ledger, entry.ledger_kind, entry.payout()).await;
+    let now = ic_cdk::api::time();
+    match result {
+        Ok(block_index) => finish(entry.id, block_index.clone(), now)
+            .map(|claim_id| (claim_id, block_index))
+            .ok_or(FaucetError::TransferInFlight {
+                journal_id: entry.id,
+            }),
+        Err(TransferFailure::Rejected(error)) => {
+            fail(entry.id, format!("{:?}", error), now);
+            Err(error)
+        }
+        Err(TransferFailure::Uncertain(error)) => {
+            JOURNAL.with(|journal| journal.borrow_mut().record_attempt(entry.id, &error, now));
+            Err(FaucetError::TransferPending {
+                journal_id: entry.id,
+            })
+        }
+        Err(TransferFailure::TooOld) => {
+            let error = FaucetError::LedgerError {
+                message: "Too old for the ledger to deduplicate; check the ledger".to_string(),
+            };
+            JOURNAL.with(|journal| journal.borrow_mut().record_attempt(entry.id, &error, now));
+            Err(FaucetError::TransferPending {
+                journal_id: entry.id,
+            })
+        }
+    }
+}
+
+// Records a paid main payout as a claim: settles its campaign reservation,
+// keeps its batch code redeemed, marks the principal as claimed and
+// journals its extra payouts. A paid extra payout is only marked as such.
+// Returns the claim id, or `None` if the entry was already resolved.
+pub fn finish(id: u64, block_index: Nat, now: u64) -> Option<u64> {
+    let main_payout = JOURNAL
+        .with(|journal| journal.borrow().get(id))?
+        .main_payout;
+    if let Some(main_id) = main_payout {
+        return finish_extra_payout(id, main_id, block_index, now);
+    }
+    let claim_id = CLAIMS.with(|claims| claims.borrow().next_id());
+    let entry = JOURNAL.with(|journal| {
+        journal.borrow_mut().resolve(
+            id,
+            TransferStatus::Succeeded {
+                block_index: block_index.clone(),
+                claim_id,
+            },
+            now,
+        )
+    })?;
+    // The payout already happened, so count it even if the campaign was
+    // paused or edited during the transfer
+    let campaign = state::mutate_campaign(entry.campaign_id, |campaign| {
+        campaign.settle(entry.amount);
+        Ok(campaign.clone())
+    })
+    .expect("Campaigns are never removed");
+    CLAIMED_PRINCIPALS.with(|principals| {
+        principals
+            .borrow_mut()
+            .insert(&campaign, entry.principal, now)
+    });
+    CLAIMS.with(|claims| {
+        claims.borrow_mut().insert(ClaimRecord {
+            id: claim_id,
+            campaign_id: entry.campaign_id,
+            principal: entry.principal,
+            destination: entry.destination,
+            amount: entry.amount,
+            fee: ledger::known_fee(entry.ledger_kind, entry.fee),
+            timestamp: now,
+            ledger: Some(entry.ledger),
+            block_index: Some(block_index),
+            status: ClaimStatus::Completed,
+            code_label: entry.code_label.clone(),
+        })
+    });
+    let capacity = state::read_config(|config| config.recent_claims_capacity);
+    RECENT_CLAIMS.with(|claims| claims.borrow_mut().push(claim_id, capacity));
+    JOURNAL.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        for payout in &entry.extra_payouts {
+            let extra = JournalEntry {
+                id: journal.next_id(),
+                ledger: payout.ledger_canister_id,
+                ledger_kind: payout.ledger_kind,
+                amount: payout.amount,
+                fee: ledger::known_fee(payout.ledger_kind, payout.transfer_fee),
+                created_at_time: now,
+                batch_code: None,
+                status: TransferStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                updated_at: now,
+                main_payout: Some(id),
+                extra_payouts: Vec::new(),
+                ..entry.clone()
+            };
+            journal.insert(extra);
+        }
+    });
+    Some(claim_id)
+}
+
+fn finish_extra_payout(id: u64, main_id: u64, block_index: Nat, now: u64) -> Option<u64> {
+    let claim_id = match JOURNAL
+        .with(|journal| journal.borrow().get(main_id))?
+        .status
+    {
+        TransferStatus::Succeeded { claim_id, .. } => claim_id,
+        _ => return None,
+    };
+    JOURNAL.with(|journal| {
+        journal.borrow_mut().resolve(
+            id,
+            TransferStatus::Succeeded {
+                block_index,
+                claim_id,
+            },
+            now,
+        )
+    })?;
+    Some(claim_id)
+}
+
+// Resolves a main payout as unpaid, handing back its campaign budget and
+// batch code. Its claim has gone through by the time an extra payout is
+// sent, so an unpaid extra payout is owed instead.
+pub fn fail(id: u64, message: String, now: u64) -> bool {
+    let is_extra_payout = JOURNAL.with(|journal| {
+        journal
+            .borrow()
+            .get(id)
+            .is_some_and(|entry| entry.main_payout.is_some())
+    });
+    if is_extra_payout {
+        return JOURNAL.with(|journal| journal.borrow_mut().owe(id, message, now).is_some());
+    }
+    let Some(entry) = JOURNAL.with(|journal| {
+        journal
+            .borrow_mut()
+            .resolve(id, TransferStatus::Failed { message }, now)
+    }) else {
+        return false;
+    };
+    let _ = state::mutate_campaign(entry.campaign_id, |campaign| {
+        campaign.release(entry.amount);
+        Ok(())
+    });
+    if let Some(code) = entry.batch_code {
+        CODE_BATCHES.with(|batches| batches.borrow_mut().unredeem(code));
+    }
+    true
+}
+
+// Gives up on an owed payout
+pub fn write_off(id: u64, now: u64) -> bool {
+    JOURNAL.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        let owed = journal
+            .get(id)
+            .is_some_and(|entry| matches!(entry.status, TransferStatus::Owed { .. }));
+        let written_off = TransferStatus::Failed {
+            message: "Written off by a custodian".to_string(),
+        };
+        owed && journal.resolve(id, written_off, now).is_some()
+    })
+}
+
+// Sends the extra payouts of a just-paid main payout, one after another.
+// Any left pending are picked up by the reconciler.
+pub async fn pay_extra_payouts(main: &JournalEntry) -> Vec<PayoutReceipt> {
+    let extra_payouts =
+        JOURNAL.with(|journal| journal.borrow().extra_payouts_of(main.principal, main.id));
+    let mut receipts = Vec::new();
+    for extra in extra_payouts {
+        if let Ok(guard) = AttemptGuard::acquire(extra.id) {
+            let _ = attempt(&guard).await;
+        }
+        receipts.extend(receipt(extra.id));
+    }
+    receipts
+}
+
+// Sends an owed payout again
+pub async fn retry_owed(id: u64) -> Result<PayoutReceipt, FaucetError> {
+    let guard = AttemptGuard::acquire(id)?;
+    JOURNAL
+        .with(|journal| journal.borrow_mut().reopen(id, ic_cdk::api::time()))
+        .ok_or_else(|| FaucetError::InvalidArgument {
+            message: format!("No owed payout with id {}", id),
+        })?;
+    let _ = attempt(&guard).await;
+    Ok(receipt(id).expect("Journal entries are never removed"))
+}
+
+pub fn receipt(id: u64) -> Option<PayoutReceipt> {
+    JOURNAL.with(|journal| journal.borrow().get(id).map(|entry| entry.receipt()))
+}
+
+pub fn start_reconciler() {
+    ic_cdk_timers::set_timer_interval(RECONCILE_INTERVAL, || ic_cdk::spawn(reconcile()));
+}
+
+// Retries pending entries that are idle and still within the ledger's
+// deduplication window. A retry of a paid transfer comes back as a
+// duplicate of it, which resolves the entry as paid.
+async fn reconcile() {
+    let now = ic_cdk::api::time();
+    let due: Vec<_> = JOURNAL
+        .with(|journal| journal.borrow().pending())
+        .into_iter()
+        .filter(|entry| {
+            now.saturating_sub(entry.updated_at) >= RECONCILE_INTERVAL.as_nanos() as u64
+        })
+        .filter(|entry| now.saturating_sub(entry.created_at_time) < DEDUP_WINDOW_NANOS)
+        .take(MAX_RETRIES_PER_RUN)
+        .collect();
+    for entry in due {
+        let Ok(guard) = AttemptGuard::acquire(entry.id) else {
+            continue;
+        };
+        let _ = attempt(&guard).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::LedgerKind;
+    use ic_stable_structures::VectorMemory;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    fn entry(id: u64, principal: u8, campaign_id: u64) -> JournalEntry {
+        JournalEntry {
+            id,
+            campaign_id,
+            principal: Principal::from_slice(&[principal]),
+            destination: Account::from(Principal::from_slice(&[principal])),
+            ledger: Principal::from_slice(&[9]),
+            ledger_kind: LedgerKind::Icrc1,
+            amount: 100,
+            fee: None,
+            created_at_time: id * NANOS_PER_SECOND,
+            code_label: None,
+            batch_code: None,
+            status: TransferStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            updated_at: 0,
+            main_payout: None,
+            extra_payouts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn entries_resolve_once() {
+        let mut journal = Journal::init(VectorMemory::default(), VectorMemory::default());
+        journal.insert(entry(0, 1, 0));
+        journal.insert(entry(1, 1, 1));
+        let principal = Principal::from_slice(&[1]);
+
+        assert_eq!(journal.pending_for(principal, 1), Some(1));
+        let failed = TransferStatus::Failed {
+            message: "refused".to_string(),
+        };
+        assert!(journal.resolve(1, failed.clone(), 5).is_some());
+        assert!(journal.resolve(1, TransferStatus::Pending, 6).is_none());
+        assert_eq!(journal.get(1).unwrap().status, failed);
+        assert_eq!(journal.pending_for(principal, 1), None);
+        assert_eq!(journal.pending_for(principal, 0), Some(0));
+    }
+
+    #[test]
+    fn long_pending_entries_are_stuck() {
+        let mut journal = Journal::init(VectorMemory::default(), VectorMemory::default());
+        journal.insert(entry(0, 1, 0));
+        journal.insert(entry(700, 2, 0));
+
+        let stuck: Vec<_> = journal
+            .stuck(700 * NANOS_PER_SECOND)
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(stuck, vec![0]);
+        assert_eq!(journal.pending().len(), 2);
+    }
+
+    #[test]
+    fn refused_extra_payouts_are_owed_until_retried() {
+        let mut journal = Journal::init(VectorMemory::default(), VectorMemory::default());
+        let principal = Principal::from_slice(&[1]);
+        journal.insert(JournalEntry {
+            main_payout: Some(0),
+            ..entry(1, 1, 0)
+        });
+        assert_eq!(journal.extra_payouts_of(principal, 0).len(), 1);
+        // A pending extra payout does not hold up further claims either
+        assert_eq!(journal.pending_for(principal, 0), None);
+
+        let owed = TransferStatus::Owed {
+            message: "refused".to_string(),
+        };
+        assert_eq!(
+            journal.owe(1, "refused".to_string(), 5).unwrap().status,
+            owed
+        );
+        // Owed payouts are neither retried nor hold up further claims
+        assert!(journal.pending().is_empty());
+        assert_eq!(journal.pending_for(principal, 0), None);
+        assert_eq!(journal.owed().len(), 1);
+
+        let reopened = journal.reopen(1, 9).unwrap();
+        assert_eq!(reopened.status, TransferStatus::Pending);
+        assert_eq!(reopened.created_at_time, 9);
+        assert!(journal.reopen(1, 10).is_none());
+        assert_eq!(journal.pending().len(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, Subaccount, Timestamp, Tokens, DEFAULT_FEE,
+    DEFAULT_SUBACCOUNT,
+};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, Memo, TransferArg, TransferError};
+
+use crate::types::FaucetError;
+
+// Which transfer interface the configured ledger canister speaks
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LedgerKind {
+    #[default]
+    Icrc1,
+    IcpLegacy,
+}
+
+// A single faucet payout
+pub struct Payout {
+    pub to: Account,
+    pub amount: u64,
+    // `None` lets an ICRC-1 ledger apply its own fee; the ICP ledger requires one,
+    // so it falls back to the standard 10_000 e8s
+    pub fee: Option<u64>,
+    // Memo and creation time let the ledger recognise a retried payout as a
+    // duplicate of the original
+    pub memo: u64,
+    pub created_at_time: u64,
+}
+
+// Why a transfer did not go through
+pub enum TransferFailure {
+    // The ledger refused it, so nothing was paid
+    Rejected(FaucetError),
+    // The call failed, so the transfer may or may not have happened
+    Uncertain(FaucetError),
+    // Too old for the ledger to deduplicate, so whether an earlier attempt
+    // was paid cannot be told from a retry
+    TooOld,
+}
+
+// Transfer a payout from the faucet's default account, returning the block
+// index. A retry of a payout that already went through returns the original
+// block.
+pub async fn transfer(
+    ledger: Principal,
+    kind: LedgerKind,
+    payout: Payout,
+) -> Result<Nat, TransferFailure> {
+    match kind {
+        LedgerKind::Icrc1 => icrc1_transfer(ledger, payout).await,
+        LedgerKind::IcpLegacy => icp_transfer(ledger, payout).await.map(Nat::from),
+    }
+}
+
+async fn icrc1_transfer(ledger: Principal, payout: Payout) -> Result<BlockIndex, TransferFailure> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: payout.to,
+        fee: payout.fee.map(Nat::from),
+        created_at_time: Some(payout.created_at_time),
+        memo: Some(Memo::from(payout.memo)),
+        amount: Nat::from(payout.amount),
+    };
+
+    let (result,): (Result<BlockIndex, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+            .await
+            .map_err(call_error)?;
+
+    result.or_else(|err| match err {
+        TransferError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        TransferError::TooOld => Err(TransferFailure::TooOld),
+        TransferError::InsufficientFunds { balance } => Err(TransferFailure::Rejected(
+            FaucetError::InsufficientFaucetBalance { balance },
+        )),
+        err => Err(TransferFailure::Rejected(FaucetError::LedgerError {
+            message: err.to_string(),
+        })),
+    })
+}
+
+async fn icp_transfer(ledger: Principal, payout: Payout) -> Result<u64, TransferFailure> {
+    let args = ic_ledger_types::TransferArgs {
+        memo: ic_ledger_types::Memo(payout.memo),
+        amount: Tokens::from_e8s(payout.amount),
+        fee: payout.fee.map(Tokens::from_e8s).unwrap_or(DEFAULT_FEE),
+        from_subaccount: None,
+        to: AccountIdentifier::new(
+            &payout.to.owner,
+            &payout.to.subaccount.map_or(DEFAULT_SUBACCOUNT, Subaccount),
+        ),
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: payout.created_at_time,
+        }),
+    };
+
+    ic_ledger_types::transfer(ledger, args)
+        .await
+        .map_err(call_error)?
+        .or_else(|err| match err {
+            ic_ledger_types::TransferError::TxDuplicate { duplicate_of } => Ok(duplicate_of),
+            ic_ledger_types::TransferError::TxTooOld { .. } => Err(TransferFailure::TooOld),
+            ic_ledger_types::TransferError::InsufficientFunds { balance } => Err(
+                TransferFailure::Rejected(FaucetError::InsufficientFaucetBalance {
+                    balance: Nat::from(balance.e8s()),
+                }),
+            ),
+            err => Err(TransferFailure::Rejected(FaucetError::LedgerError {
+                message: err.to_string(),
+            })),
+        })
+}
+
+// The balance of the faucet's default account
+pub async fn balance(ledger: Principal, kind: LedgerKind) -> Result<Nat, FaucetError> {
+    let faucet = ic_cdk::id();
+    match kind {
+        LedgerKind::Icrc1 => {
+            let (balance,): (Nat,) =
+                ic_cdk::call(ledger, "icrc1_balance_of", (Account::from(faucet),))
+                    .await
+                    .map_err(ledger_error)?;
+            Ok(balance)
+        }
+        LedgerKind::IcpLegacy => {
+            let args = AccountBalanceArgs {
+                account: AccountIdentifier::new(&faucet, &DEFAULT_SUBACCOUNT),
+            };
+            let balance = ic_ledger_types::account_balance(ledger, args)
+                .await
+                .map_err(ledger_error)?;
+            Ok(Nat::from(balance.e8s()))
+        }
+    }
+}
+
+// The fee a payout is sent with, if known without asking the ledger: the
+// configured one, or the fee the ICP ledger requires
+pub fn known_fee(kind: LedgerKind, configured: Option<u64>) -> Option<u64> {
+    match kind {
+        LedgerKind::Icrc1 => configured,
+        LedgerKind::IcpLegacy => Some(configured.unwrap_or(DEFAULT_FEE.e8s())),
+    }
+}
+
+// The fee a payout is charged: the known one, or else what the ledger
+// charges by default
+pub async fn fee(
+    ledger: Principal,
+    kind: LedgerKind,
+    configured: Option<u64>,
+) -> Result<u64, FaucetError> {
+    if let Some(fee) = known_fee(kind, configured) {
+        return Ok(fee);
+    }
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(ledger_error)?;
+    u64::try_from(fee.0).map_err(|_| FaucetError::LedgerError {
+        message: "Ledger fee does not fit in 64 bits".to_string(),
+    })
+}
+
+// Only some rejects leave it open whether the ledger ran the transfer. A
+// missing ledger or method, the ledger refusing the call, or a fatal system
+// error all mean it never did. A canister error may be a failure to decode
+// the reply after the transfer went through.
+fn call_error(error: (RejectionCode, String)) -> TransferFailure {
+    match error.0 {
+        RejectionCode::DestinationInvalid
+        | RejectionCode::CanisterReject
+        | RejectionCode::SysFatal => TransferFailure::Rejected(ledger_error(error)),
+        _ => TransferFailure::Uncertain(ledger_error(error)),
+    }
+}
+
+fn ledger_error((code, message): (RejectionCode, String)) -> FaucetError {
+    FaucetError::LedgerError {
+        message: format!("Ledger call failed: {:?} {}", code, message),
+    }
+}
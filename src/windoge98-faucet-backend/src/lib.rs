@@ -1,64 +1,92 @@
 extern crate ic_cdk_macros;
 extern crate serde;
 
-use std::cell::RefCell;
-use std::collections::{HashSet, VecDeque};
-
-use candid::{CandidType, Deserialize, Principal};
+use candid::{Nat, Principal};
 use ic_cdk::api;
+use ic_cdk::api::management_canister::main::raw_rand;
 use ic_cdk::*;
+use icrc_ledger_types::icrc1::account::Account;
 
-// State struct definition (Canister Storage)
-#[derive(CandidType, Deserialize, Default)]
-struct State {
-    custodians: HashSet<Principal>,
-    is_faucet_enabled: bool,
-    faucet_code: String,
-    faucet_amount: u64,
-    claimed_principals: Vec<Principal>,
-    recent_claims: VecDeque<(Principal, u64)>,
-    total_claims: Vec<(Principal, u64)>,
-}
+mod balances;
+mod batches;
+mod callers;
+mod campaigns;
+mod claims;
+mod codes;
+mod inflight;
+mod journal;
+mod ledger;
+mod lockouts;
+mod migrations;
+mod recent;
+mod registry;
+mod schedule;
+mod state;
+mod types;
 
-// Globals: thread_local!
-thread_local! {
-    static STATE: RefCell<State> = RefCell::default();
-}
+use batches::MAX_BATCH_SIZE;
+use campaigns::{DEFAULT_CAMPAIGN_ID, DEFAULT_CAMPAIGN_NAME};
+use codes::DEFAULT_CODE_LABEL;
+use inflight::{AttemptGuard, ClaimGuard};
+use ledger::LedgerKind;
+use recent::MAX_RECENT_CLAIMS_CAPACITY;
+use state::{
+    Config, CAMPAIGNS, CLAIMED_PRINCIPALS, CLAIMS, CODE_BATCHES, JOURNAL, LOCKOUTS, RECENT_CLAIMS,
+    SCHEDULE,
+};
+use types::{
+    CallerPolicy, Campaign, CampaignArgs, CampaignInfo, CampaignStatus, ClaimMode, ClaimPage,
+    ClaimQuery, ClaimReceipt, ClaimRecord, CodeBatchInfo, CodeEntry, CodeInput, CodeNormalization,
+    DestinationPolicy, FaucetBalance, FaucetError, GeneratedCodeBatch, JournalEntry, Lockout,
+    LockoutPolicy, LowBalanceAlarm, PayoutReceipt, Redemption, ScheduledToggle, TokenPayout,
+    TransferStatus, MAX_PAGE_SIZE,
+};
 
 // Canister initialization
 #[init]
 fn init() {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        state.custodians.insert(api::caller());
+    state::mutate_config(|config| {
+        config.custodians.insert(api::caller());
     });
-}
-
-// Pre-upgrade hook
-#[pre_upgrade]
-fn pre_upgrade() {
-    STATE.with(|state| {
-        let state = state.borrow();
-        let owned_state = State {
-            custodians: state.custodians.clone(),
-            is_faucet_enabled: state.is_faucet_enabled,
-            faucet_code: state.faucet_code.clone(),
-            faucet_amount: state.faucet_amount,
-            claimed_principals: state.claimed_principals.clone(),
-            recent_claims: state.recent_claims.clone(),
-            total_claims: state.total_claims.clone(),
-        };
-        ic_cdk::storage::stable_save((owned_state,)).unwrap();
+    CAMPAIGNS.with(|campaigns| {
+        campaigns.borrow_mut().insert(Campaign {
+            id: DEFAULT_CAMPAIGN_ID,
+            name: DEFAULT_CAMPAIGN_NAME.to_string(),
+            ..Default::default()
+        })
     });
+    journal::start_reconciler();
+    balances::start_monitor();
 }
 
 // Post-upgrade hook
 #[post_upgrade]
 fn post_upgrade() {
-    let (state,): (State,) = ic_cdk::storage::stable_restore().unwrap();
-    STATE.with(|state0| {
-        *state0.borrow_mut() = state;
-    });
+    migrations::run();
+    schedule::rearm();
+    journal::start_reconciler();
+    balances::start_monitor();
+}
+
+// Only custodians may call configuration endpoints
+fn require_custodian(config: &Config) -> Result<(), FaucetError> {
+    if config.custodians.contains(&api::caller()) {
+        Ok(())
+    } else {
+        Err(FaucetError::NotCustodian)
+    }
+}
+
+// Apply a custodian change to a campaign, unless it is closed for good
+fn update_campaign_as_custodian(
+    campaign_id: u64,
+    f: impl FnOnce(&mut Campaign) -> Result<(), FaucetError>,
+) -> Result<(), FaucetError> {
+    state::read_config(require_custodian)?;
+    state::mutate_campaign(campaign_id, |campaign| {
+        campaign.require_not_closed()?;
+        f(campaign)
+    })
 }
 
 // ----------------------------------------------
@@ -67,121 +95,728 @@ fn post_upgrade() {
 
 // Add a new custodian
 #[update]
-fn add_custodian(custodian: Principal) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can add new custodians"
-        );
-        state.custodians.insert(custodian);
-    });
+fn add_custodian(custodian: Principal) -> Result<(), FaucetError> {
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.custodians.insert(custodian);
+        Ok(())
+    })
 }
 
 // Remove a custodian
 #[update]
-fn remove_custodian(custodian: Principal) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can remove custodians"
-        );
-        state.custodians.remove(&custodian);
-    });
+fn remove_custodian(custodian: Principal) -> Result<(), FaucetError> {
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.custodians.remove(&custodian);
+        Ok(())
+    })
 }
 
 // Toggle faucet on/off
 #[update]
-fn toggle_faucet(is_enabled: bool) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can toggle the faucet"
-        );
-        state.is_faucet_enabled = is_enabled;
-    });
+fn toggle_faucet(is_enabled: bool) -> Result<(), FaucetError> {
+    let status = if is_enabled {
+        CampaignStatus::Active
+    } else {
+        CampaignStatus::Paused
+    };
+    set_campaign_status(DEFAULT_CAMPAIGN_ID, status)
 }
 
-// Set faucet code
+// Set faucet code, in plaintext or as a salted hash; only the hash is kept.
+// Replaces any other active codes of the default campaign.
 #[update]
-fn set_faucet_code(code: String) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can set the faucet code"
-        );
-        state.faucet_code = code;
-    });
+fn set_faucet_code(code: CodeInput) -> Result<(), FaucetError> {
+    codes::validate(&code)?;
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        let entry = CodeEntry {
+            label: DEFAULT_CODE_LABEL.to_string(),
+            code,
+        };
+        campaign.set_codes(vec![entry], campaign.normalization, api::time());
+        Ok(())
+    })
+}
+
+// Replace a campaign's active codes and how submitted codes are normalized
+#[update]
+fn set_faucet_codes(
+    codes: Vec<CodeEntry>,
+    normalization: CodeNormalization,
+    campaign_id: Option<u64>,
+) -> Result<(), FaucetError> {
+    codes::validate_entries(&codes)?;
+    update_campaign_as_custodian(campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID), |campaign| {
+        campaign.set_codes(codes, normalization, api::time());
+        Ok(())
+    })
 }
 
 // Set faucet amount
 #[update]
-fn set_faucet_amount(amount: u64) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can set the faucet amount"
-        );
-        state.faucet_amount = amount;
-    });
+fn set_faucet_amount(amount: u64) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.amount = amount;
+        Ok(())
+    })
 }
 
-// Reset claimed principals
+// Set ledger canister the faucet pays out from
 #[update]
-fn reset_claimed_principals() {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(
-            state.custodians.contains(&api::caller()),
-            "Only custodians can reset claimed principals"
-        );
-        state.claimed_principals.clear();
-    });
+fn set_ledger_canister_id(ledger_canister_id: Principal) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.ledger_canister_id = Some(ledger_canister_id);
+        Ok(())
+    })
 }
 
-// Claim faucet
+// Set which transfer interface the ledger canister uses
 #[update]
-fn claim_faucet(code: String) {
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        assert!(state.is_faucet_enabled, "Faucet is currently disabled");
-        assert_eq!(code, state.faucet_code, "Invalid faucet code");
+fn set_ledger_kind(ledger_kind: LedgerKind) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.ledger_kind = ledger_kind;
+        Ok(())
+    })
+}
 
-        let caller = api::caller();
-        assert!(
-            !state.claimed_principals.contains(&caller),
-            "Principal has already claimed from the faucet"
-        );
+// Set transfer fee, or clear it to use the ledger default
+#[update]
+fn set_transfer_fee(fee: Option<u64>) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.transfer_fee = fee;
+        Ok(())
+    })
+}
+
+// Set how many claims the recent claims buffer keeps
+#[update]
+fn set_recent_claims_capacity(capacity: u64) -> Result<(), FaucetError> {
+    if capacity > MAX_RECENT_CLAIMS_CAPACITY {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("Capacity cannot exceed {}", MAX_RECENT_CLAIMS_CAPACITY),
+        });
+    }
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.recent_claims_capacity = capacity;
+        Ok(())
+    })?;
+    RECENT_CLAIMS.with(|claims| claims.borrow_mut().truncate(capacity));
+    Ok(())
+}
+
+// Set how many wrong codes a principal may submit before it is locked out
+#[update]
+fn set_lockout_policy(policy: LockoutPolicy) -> Result<(), FaucetError> {
+    lockouts::validate_policy(&policy)?;
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.lockout_policy = policy;
+        Ok(())
+    })
+}
+
+// Set which kinds of principal may claim
+#[update]
+fn set_caller_policy(policy: CallerPolicy) -> Result<(), FaucetError> {
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.caller_policy = policy;
+        Ok(())
+    })
+}
 
-        let faucet_amount = state.faucet_amount;
+// Set which accounts claims may be paid to
+#[update]
+fn set_destination_policy(policy: DestinationPolicy) -> Result<(), FaucetError> {
+    state::mutate_config(|config| {
+        require_custodian(config)?;
+        config.destination_policy = policy;
+        Ok(())
+    })
+}
+
+// Resolve a pending payout by hand, after checking the ledger: with the
+// block that paid it, or `None` if it was never paid
+#[update]
+fn resolve_stuck_claim(journal_id: u64, block_index: Option<Nat>) -> Result<(), FaucetError> {
+    state::read_config(require_custodian)?;
+    let _guard = AttemptGuard::acquire(journal_id)?;
+    let now = api::time();
+    let resolved = match block_index {
+        Some(block_index) => journal::finish(journal_id, block_index, now).is_some(),
+        None => journal::fail(
+            journal_id,
+            "Resolved as unpaid by a custodian".to_string(),
+            now,
+        ),
+    };
+    if !resolved {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("No pending journal entry with id {}", journal_id),
+        });
+    }
+    Ok(())
+}
+
+// Send an owed extra payout again
+#[update]
+async fn retry_owed_payout(journal_id: u64) -> Result<PayoutReceipt, FaucetError> {
+    state::read_config(require_custodian)?;
+    journal::retry_owed(journal_id).await
+}
+
+// Give up on an owed extra payout
+#[update]
+fn write_off_owed_payout(journal_id: u64) -> Result<(), FaucetError> {
+    state::read_config(require_custodian)?;
+    let _guard = AttemptGuard::acquire(journal_id)?;
+    if !journal::write_off(journal_id, api::time()) {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("No owed payout with id {}", journal_id),
+        });
+    }
+    Ok(())
+}
+
+// Lift a principal's lockout and forget its failed attempts
+#[update]
+fn clear_lockout(principal: Principal) -> Result<(), FaucetError> {
+    state::read_config(require_custodian)?;
+    LOCKOUTS.with(|lockouts| lockouts.borrow_mut().clear(principal));
+    Ok(())
+}
+
+// Alert, and optionally pause, when the faucet's balance for a campaign (the
+// default one if omitted) drops below a threshold
+#[update]
+fn set_low_balance_alarm(
+    alarm: Option<LowBalanceAlarm>,
+    campaign_id: Option<u64>,
+) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID), |campaign| {
+        campaign.low_balance = alarm;
+        Ok(())
+    })
+}
+
+// Pay these out alongside each claim from a campaign (the default one if
+// omitted)
+#[update]
+fn set_extra_payouts(
+    payouts: Vec<TokenPayout>,
+    campaign_id: Option<u64>,
+) -> Result<(), FaucetError> {
+    campaigns::validate_extra_payouts(&payouts)?;
+    update_campaign_as_custodian(campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID), |campaign| {
+        campaign.extra_payouts = payouts;
+        Ok(())
+    })
+}
 
-        // TODO: Implement token transfer logic
-        // transfer(caller, faucet_amount);
+// Cap the faucet's total payout and number of claims; it pauses itself
+// once either is reached
+#[update]
+fn set_faucet_budget(budget: Option<u64>, max_claims: Option<u64>) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.budget = budget;
+        campaign.max_claims = max_claims;
+        Ok(())
+    })
+}
+
+// Switch between one-shot and cooldown (drip) claims
+#[update]
+fn set_claim_mode(claim_mode: ClaimMode) -> Result<(), FaucetError> {
+    campaigns::validate_claim_mode(claim_mode)?;
+    update_campaign_as_custodian(DEFAULT_CAMPAIGN_ID, |campaign| {
+        campaign.claim_mode = claim_mode;
+        Ok(())
+    })
+}
+
+// Reset claimed principals of a campaign (the default one if omitted)
+#[update]
+fn reset_claimed_principals(campaign_id: Option<u64>) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID), |campaign| {
+        campaign.claims_epoch += 1;
+        Ok(())
+    })
+}
+
+// Create a campaign, returning its id
+#[update]
+fn create_campaign(args: CampaignArgs) -> Result<u64, FaucetError> {
+    state::read_config(require_custodian)?;
+    campaigns::validate(&args)?;
+    Ok(CAMPAIGNS.with(|campaigns| {
+        let mut campaigns = campaigns.borrow_mut();
+        let id = campaigns.next_id();
+        campaigns.insert(Campaign::new(id, args, api::time()));
+        id
+    }))
+}
+
+// Replace a campaign's settings
+#[update]
+fn update_campaign(campaign_id: u64, args: CampaignArgs) -> Result<(), FaucetError> {
+    campaigns::validate(&args)?;
+    update_campaign_as_custodian(campaign_id, |campaign| {
+        campaign.apply(args, api::time());
+        Ok(())
+    })
+}
+
+// Schedule a campaign (the default one if omitted) to switch on or off at
+// `at`, in nanoseconds since the epoch
+#[update]
+fn schedule_toggle(
+    campaign_id: Option<u64>,
+    at: u64,
+    is_enabled: bool,
+) -> Result<u64, FaucetError> {
+    let campaign_id = campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID);
+    state::read_config(require_custodian)?;
+    state::read_campaign(campaign_id, Campaign::require_not_closed)?;
+    if at < api::time() {
+        return Err(FaucetError::InvalidArgument {
+            message: "Scheduled time is in the past".to_string(),
+        });
+    }
+    let id = SCHEDULE.with(|schedule| schedule.borrow_mut().insert(at, campaign_id, is_enabled));
+    schedule::rearm();
+    Ok(id)
+}
+
+// Cancel a scheduled toggle
+#[update]
+fn cancel_scheduled_toggle(schedule_id: u64) -> Result<(), FaucetError> {
+    state::read_config(require_custodian)?;
+    if !SCHEDULE.with(|schedule| schedule.borrow_mut().remove(schedule_id)) {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("No scheduled toggle with id {}", schedule_id),
+        });
+    }
+    schedule::rearm();
+    Ok(())
+}
 
-        state.claimed_principals.push(caller);
-        state.recent_claims.push_back((caller, faucet_amount));
-        state.total_claims.push((caller, faucet_amount));
+// Add a batch of single-use codes to a campaign; only their hashes are kept
+#[update]
+fn add_code_batch(campaign_id: u64, codes: Vec<String>) -> Result<u64, FaucetError> {
+    state::read_config(require_custodian)?;
+    let normalization = state::read_campaign(campaign_id, |campaign| {
+        campaign.require_not_closed()?;
+        Ok(campaign.normalization)
+    })?;
+    let now = api::time();
+    CODE_BATCHES.with(|batches| {
+        batches.borrow_mut().add(
+            campaign_id,
+            &codes,
+            normalization,
+            codes::salt(campaign_id, now),
+            now,
+        )
+    })
+}
+
+// Generate a batch of random single-use codes for a campaign. The codes are
+// returned once and cannot be recovered later.
+#[update]
+async fn generate_code_batch(
+    campaign_id: u64,
+    count: u64,
+) -> Result<GeneratedCodeBatch, FaucetError> {
+    state::read_config(require_custodian)?;
+    state::read_campaign(campaign_id, Campaign::require_not_closed)?;
+    if count == 0 || count > MAX_BATCH_SIZE as u64 {
+        return Err(FaucetError::InvalidArgument {
+            message: format!("A batch holds 1 to {} codes", MAX_BATCH_SIZE),
+        });
+    }
+    let (seed,) =
+        raw_rand()
+            .await
+            .map_err(|(code, message)| FaucetError::RandomnessUnavailable {
+                message: format!("{:?} {}", code, message),
+            })?;
+    let codes = batches::generate_codes(&seed, count as usize);
+    let batch_id = add_code_batch(campaign_id, codes.clone())?;
+    Ok(GeneratedCodeBatch { batch_id, codes })
+}
+
+// Pause a campaign
+#[update]
+fn pause_campaign(campaign_id: u64) -> Result<(), FaucetError> {
+    set_campaign_status(campaign_id, CampaignStatus::Paused)
+}
+
+// Resume a paused campaign
+#[update]
+fn resume_campaign(campaign_id: u64) -> Result<(), FaucetError> {
+    set_campaign_status(campaign_id, CampaignStatus::Active)
+}
+
+// Close a campaign for good
+#[update]
+fn close_campaign(campaign_id: u64) -> Result<(), FaucetError> {
+    set_campaign_status(campaign_id, CampaignStatus::Closed)
+}
+
+fn set_campaign_status(campaign_id: u64, status: CampaignStatus) -> Result<(), FaucetError> {
+    update_campaign_as_custodian(campaign_id, |campaign| {
+        campaign.status = status;
+        Ok(())
+    })
+}
+
+// Claim faucet from a campaign (the default one if omitted)
+#[update]
+async fn claim_faucet(
+    code: String,
+    campaign_id: Option<u64>,
+    destination: Option<Account>,
+) -> Result<ClaimReceipt, FaucetError> {
+    let caller = api::caller();
+    let now = api::time();
+    let campaign_id = campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID);
+    // Claims are tracked against the caller whichever account is paid
+    let destination = destination.unwrap_or_else(|| Account::from(caller));
+    state::read_config(|config| {
+        config.caller_policy.check(caller)?;
+        config.destination_policy.check(caller, &destination)
+    })?;
+    LOCKOUTS.with(|lockouts| lockouts.borrow().check(caller, now))?;
+    let claim = begin_claim(caller, campaign_id, &code, destination, now)?;
+    let (claim_id, block_index) = journal::attempt(&claim.attempt).await?;
+    // The claim stands from here on; extra payouts the ledger refuses are
+    // owed rather than undoing it
+    let extra_payouts = journal::pay_extra_payouts(&claim.entry).await;
+    Ok(ClaimReceipt {
+        claim_id,
+        block_index,
+        amount: claim.entry.amount,
+        extra_payouts,
+    })
+}
+
+// A claim that has passed its checks and holds its reservation in the
+// journal, ready for the transfer. The principal stays in flight until it is
+// dropped.
+struct StartedClaim {
+    entry: JournalEntry,
+    attempt: AttemptGuard,
+    _claim: ClaimGuard,
+}
+
+// Everything `claim_faucet` does before calling the ledger
+fn begin_claim(
+    caller: Principal,
+    campaign_id: u64,
+    code: &str,
+    destination: Account,
+    now: u64,
+) -> Result<StartedClaim, FaucetError> {
+    // Held until the claim is recorded, so a concurrent claim from the same
+    // principal cannot pass the claimed check in the meantime
+    let claim = ClaimGuard::acquire(caller)?;
+    // A payout that may already have gone through counts as a claim
+    if let Some(journal_id) =
+        JOURNAL.with(|journal| journal.borrow().pending_for(caller, campaign_id))
+    {
+        return Err(FaucetError::TransferPending { journal_id });
+    }
+    // Checks and the budget reservation happen in one step, so concurrent
+    // claims cannot overspend the campaign
+    let checked = state::mutate_campaign(campaign_id, |campaign| {
+        campaign.check_open(now)?;
+        // One of the shared codes, or failing that an unredeemed
+        // single-use one
+        let (code_label, batch_code) = match campaign.check_code(code, now) {
+            Ok(label) => (Some(label), None),
+            Err(_) => (
+                None,
+                Some(CODE_BATCHES.with(|batches| batches.borrow().check(campaign_id, code))?),
+            ),
+        };
+        CLAIMED_PRINCIPALS.with(|principals| principals.borrow().check(campaign, caller, now))?;
+        let ledger_canister_id = campaign
+            .ledger_canister_id
+            .ok_or(FaucetError::LedgerNotConfigured)?;
+        campaign.reserve()?;
+        Ok(JournalEntry {
+            id: JOURNAL.with(|journal| journal.borrow().next_id()),
+            campaign_id,
+            principal: caller,
+            destination,
+            ledger: ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            amount: campaign.amount,
+            fee: ledger::known_fee(campaign.ledger_kind, campaign.transfer_fee),
+            created_at_time: now,
+            code_label,
+            batch_code,
+            status: TransferStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            updated_at: now,
+            main_payout: None,
+            extra_payouts: campaign.extra_payouts.clone(),
+        })
     });
+    // Wrong codes count towards a lockout; a right one wipes the slate
+    let entry = match checked {
+        Err(error @ (FaucetError::InvalidCode | FaucetError::CodeAlreadyRedeemed)) => {
+            let policy = state::read_config(|config| config.lockout_policy.clone());
+            return Err(LOCKOUTS.with(|lockouts| {
+                lockouts
+                    .borrow_mut()
+                    .record_failure(caller, now, &policy, error)
+            }));
+        }
+        checked => checked?,
+    };
+    LOCKOUTS.with(|lockouts| lockouts.borrow_mut().clear(caller));
+    // The journal entry now holds the reservation and batch code until the
+    // transfer is resolved, by this call or the reconciler
+    if let Some(code) = entry.batch_code {
+        CODE_BATCHES.with(|batches| batches.borrow_mut().redeem(code, caller, now));
+    }
+    JOURNAL.with(|journal| journal.borrow_mut().insert(entry.clone()));
+    let attempt = AttemptGuard::acquire(entry.id)?;
+    Ok(StartedClaim {
+        entry,
+        attempt,
+        _claim: claim,
+    })
 }
 
-// Get recent claims
+// Get every campaign
 #[query]
-fn get_recent_claims() -> Vec<(Principal, u64)> {
-    STATE.with(|state| {
-        let state = state.borrow();
-        state.recent_claims.iter().cloned().collect()
+fn get_campaigns() -> Vec<CampaignInfo> {
+    CAMPAIGNS.with(|campaigns| {
+        campaigns
+            .borrow()
+            .list()
+            .iter()
+            .map(CampaignInfo::from)
+            .collect()
     })
 }
 
-// Get total claims
+// Get pending scheduled toggles, soonest first
+#[query]
+fn get_scheduled_toggles() -> Vec<ScheduledToggle> {
+    SCHEDULE.with(|schedule| schedule.borrow().list())
+}
+
+// Get a campaign's code batches and how many codes each has left
+#[query]
+fn get_code_batches(campaign_id: u64) -> Vec<CodeBatchInfo> {
+    CODE_BATCHES.with(|batches| batches.borrow().info(campaign_id))
+}
+
+// Get who redeemed which codes of a batch
+#[query]
+fn get_code_redemptions(batch_id: u64) -> Result<Vec<Redemption>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(CODE_BATCHES.with(|batches| batches.borrow().redemptions(batch_id)))
+}
+
+// Get which kinds of principal may claim
+#[query]
+fn get_caller_policy() -> CallerPolicy {
+    state::read_config(|config| config.caller_policy.clone())
+}
+
+// Get the faucet's balance for a campaign (the default one if omitted), and
+// how many claims it covers. Custodians get it from the ledger; since that
+// costs the faucet cycles, anyone else gets the balance monitor's latest
+// check.
+#[update]
+async fn faucet_balance(campaign_id: Option<u64>) -> Result<FaucetBalance, FaucetError> {
+    let campaign_id = campaign_id.unwrap_or(DEFAULT_CAMPAIGN_ID);
+    if state::read_config(require_custodian).is_ok() {
+        balances::check(campaign_id).await
+    } else {
+        balances::latest(campaign_id).ok_or(FaucetError::BalanceNotChecked)
+    }
+}
+
+// Get the campaigns whose balance was low when last checked
+#[query]
+fn get_balance_alarms() -> Vec<FaucetBalance> {
+    balances::alarms()
+}
+
+// Get which accounts claims may be paid to
+#[query]
+fn get_destination_policy() -> DestinationPolicy {
+    state::read_config(|config| config.destination_policy)
+}
+
+// Get the code guessing lockout policy
 #[query]
-fn get_total_claims() -> Vec<(Principal, u64)> {
-    STATE.with(|state| {
-        let state = state.borrow();
-        state.total_claims.clone()
+fn get_lockout_policy() -> LockoutPolicy {
+    state::read_config(|config| config.lockout_policy.clone())
+}
+
+// Get every principal that is currently locked out
+#[query]
+fn get_lockouts() -> Result<Vec<Lockout>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(LOCKOUTS.with(|lockouts| lockouts.borrow().locked(api::time())))
+}
+
+// Get a principal's failed code attempts, if it has any
+#[query]
+fn get_lockout(principal: Principal) -> Result<Option<Lockout>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(LOCKOUTS.with(|lockouts| lockouts.borrow().get(principal)))
+}
+
+// Get payouts that have been pending for a while: the ledger call failed
+// and the reconciler has not been able to resolve them
+#[query]
+fn get_stuck_claims() -> Result<Vec<JournalEntry>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(JOURNAL.with(|journal| journal.borrow().stuck(api::time())))
+}
+
+// Get extra payouts the ledger refused, oldest first
+#[query]
+fn get_owed_payouts() -> Result<Vec<JournalEntry>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(JOURNAL.with(|journal| journal.borrow().owed()))
+}
+
+// Get a payout journal entry
+#[query]
+fn get_journal_entry(journal_id: u64) -> Result<Option<JournalEntry>, FaucetError> {
+    state::read_config(require_custodian)?;
+    Ok(JOURNAL.with(|journal| journal.borrow().get(journal_id)))
+}
+
+// Get a single campaign
+#[query]
+fn get_campaign(campaign_id: u64) -> Option<CampaignInfo> {
+    CAMPAIGNS.with(|campaigns| {
+        campaigns
+            .borrow()
+            .get(campaign_id)
+            .as_ref()
+            .map(CampaignInfo::from)
     })
 }
+
+// Get recent claims, newest first
+#[query]
+fn get_recent_claims() -> Vec<ClaimRecord> {
+    let claim_ids = RECENT_CLAIMS.with(|claims| {
+        let claims = claims.borrow();
+        claims.page(0, claims.len())
+    });
+    CLAIMS.with(|claims| claims.borrow().get_many(claim_ids))
+}
+
+// Get a page of recent claims, newest first
+#[query]
+fn get_recent_claims_page(offset: u64, limit: u64) -> Vec<ClaimRecord> {
+    let claim_ids =
+        RECENT_CLAIMS.with(|claims| claims.borrow().page(offset, limit.min(MAX_PAGE_SIZE)));
+    CLAIMS.with(|claims| claims.borrow().get_many(claim_ids))
+}
+
+// Get a page of the claim history, oldest first
+#[query]
+fn get_claims(query: ClaimQuery) -> ClaimPage {
+    CLAIMS.with(|claims| claims.borrow().query(&query))
+}
+
+// Get every claim made by a principal
+#[query]
+fn get_claims_for(principal: Principal) -> Vec<ClaimRecord> {
+    CLAIMS.with(|claims| claims.borrow().for_principal(principal))
+}
+
+// Get the total number of claims
+#[query]
+fn get_claim_count() -> u64 {
+    CLAIMS.with(|claims| claims.borrow().len())
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid_parser::utils::{service_equal, CandidSource};
+
+    // Fails when the checked-in .did drifts from the exported endpoints.
+    // Run with UPDATE_CANDID=1 to regenerate the file.
+    #[test]
+    fn candid_interface_is_up_to_date() {
+        let did_path =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("windoge98-faucet-backend.did");
+        let exported = super::__export_service();
+
+        if std::env::var_os("UPDATE_CANDID").is_some() {
+            std::fs::write(&did_path, &exported).unwrap();
+            return;
+        }
+
+        let declared = std::fs::read_to_string(&did_path).unwrap();
+        service_equal(CandidSource::Text(&exported), CandidSource::Text(&declared))
+            .expect("windoge98-faucet-backend.did is out of date, rerun with UPDATE_CANDID=1");
+    }
+
+    // Claims in progress interleave at the ledger call, which is where
+    // `claim_faucet` awaits once `begin_claim` returns
+    #[test]
+    fn interleaved_claims_from_one_principal_pay_once() {
+        let caller = Principal::from_slice(&[1]);
+        let destination = Account::from(caller);
+        let mut campaign = Campaign {
+            id: 4,
+            amount: 100,
+            ledger_canister_id: Some(Principal::from_slice(&[9])),
+            status: CampaignStatus::Active,
+            ..Default::default()
+        };
+        campaign.set_codes(
+            vec![CodeEntry {
+                label: "default".to_string(),
+                code: CodeInput::Plaintext("Windoge".to_string()),
+            }],
+            Default::default(),
+            0,
+        );
+        state::CAMPAIGNS.with(|campaigns| campaigns.borrow_mut().insert(campaign));
+
+        let first = begin_claim(caller, 4, "Windoge", destination, 10).unwrap();
+        // The first claim is awaiting the ledger and has not recorded yet
+        assert_eq!(
+            begin_claim(caller, 4, "Windoge", destination, 11).err(),
+            Some(FaucetError::ClaimInProgress)
+        );
+
+        // Dropped mid-await, as ic-cdk does when the callback traps: the
+        // principal is released but the transfer may have gone through
+        let id = first.entry.id;
+        drop(first);
+        assert_eq!(
+            begin_claim(caller, 4, "Windoge", destination, 12).err(),
+            Some(FaucetError::TransferPending { journal_id: id })
+        );
+
+        journal::finish(id, candid::Nat::from(5u64), 13);
+        assert_eq!(
+            begin_claim(caller, 4, "Windoge", destination, 14).err(),
+            Some(FaucetError::AlreadyClaimed)
+        );
+        let campaign = state::read_campaign(4, |campaign| Ok(campaign.clone())).unwrap();
+        assert_eq!((campaign.disbursed, campaign.reserved_amount), (100, 0));
+    }
+}
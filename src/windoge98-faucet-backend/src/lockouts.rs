@@ -0,0 +1,272 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::types::{FaucetError, Lockout, LockoutPolicy, NANOS_PER_SECOND};
+
+// Entries looked at for pruning per recorded failure. Each failure adds at
+// most one entry, so pruning keeps going round the map faster than it grows.
+const PRUNE_PER_FAILURE: usize = 4;
+
+// A principal's wrong code guesses. `failures` counts those since
+// `window_start`; `strikes` counts lockouts so far, each one doubling the
+// next lockout's length.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FailedAttempts {
+    pub window_start: u64,
+    pub failures: u64,
+    pub strikes: u64,
+    pub locked_until: u64,
+}
+
+// Failed code attempts by principal. Principals are forgotten once a claim
+// of theirs gets past the code check, or once nothing they did still counts.
+pub struct Lockouts<M: Memory> {
+    attempts: StableBTreeMap<Principal, FailedAttempts, M>,
+    // Where the next pruning pass starts. Heap only: after an upgrade
+    // pruning starts over from the first principal.
+    prune_from: Option<Principal>,
+}
+
+impl<M: Memory> Lockouts<M> {
+    pub fn init(memory: M) -> Self {
+        Self {
+            attempts: StableBTreeMap::init(memory),
+            prune_from: None,
+        }
+    }
+
+    pub fn check(&self, principal: Principal, now: u64) -> Result<(), FaucetError> {
+        match self.attempts.get(&principal) {
+            Some(attempts) if now < attempts.locked_until => Err(FaucetError::LockedOut {
+                locked_until: attempts.locked_until,
+                strikes: attempts.strikes,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Counts a wrong code. Returns the lockout error if this failure hit the
+    // policy's limit, otherwise `error` unchanged.
+    pub fn record_failure(
+        &mut self,
+        principal: Principal,
+        now: u64,
+        policy: &LockoutPolicy,
+        error: FaucetError,
+    ) -> FaucetError {
+        let mut attempts = self.attempts.get(&principal).unwrap_or_default();
+        // Strikes are forgiven after a full maximum lockout without failures
+        let forgiven_at = attempts
+            .locked_until
+            .saturating_add(seconds(policy.max_lockout_seconds));
+        if attempts.strikes > 0 && now >= forgiven_at {
+            attempts.strikes = 0;
+        }
+        let window_end = attempts
+            .window_start
+            .saturating_add(seconds(policy.window_seconds));
+        if now >= window_end {
+            attempts.window_start = now;
+            attempts.failures = 0;
+        }
+        attempts.failures += 1;
+
+        let error = if attempts.failures >= policy.max_failures {
+            let backoff = 1u64
+                .checked_shl(attempts.strikes as u32)
+                .unwrap_or(u64::MAX);
+            let lockout_seconds = policy
+                .lockout_seconds
+                .saturating_mul(backoff)
+                .min(policy.max_lockout_seconds);
+            attempts.strikes += 1;
+            attempts.failures = 0;
+            attempts.window_start = now;
+            attempts.locked_until = now.saturating_add(seconds(lockout_seconds));
+            FaucetError::LockedOut {
+                locked_until: attempts.locked_until,
+                strikes: attempts.strikes,
+            }
+        } else {
+            error
+        };
+        self.attempts.insert(principal, attempts);
+        self.prune(now, policy);
+        error
+    }
+
+    // Drops the next few entries that no longer affect anyone, wrapping
+    // round to the first principal at the end of the map
+    fn prune(&mut self, now: u64, policy: &LockoutPolicy) {
+        let start = self.prune_from.take();
+        let mut seen: Vec<_> = match start {
+            Some(start) => self
+                .attempts
+                .range(start..)
+                .take(PRUNE_PER_FAILURE + 1)
+                .collect(),
+            None => self.attempts.iter().take(PRUNE_PER_FAILURE + 1).collect(),
+        };
+        if seen.len() > PRUNE_PER_FAILURE {
+            self.prune_from = seen.pop().map(|(principal, _)| principal);
+        }
+        for (principal, attempts) in seen {
+            if is_spent(&attempts, now, policy) {
+                self.attempts.remove(&principal);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, principal: Principal) -> bool {
+        self.attempts.remove(&principal).is_some()
+    }
+
+    pub fn get(&self, principal: Principal) -> Option<Lockout> {
+        self.attempts
+            .get(&principal)
+            .map(|attempts| lockout(principal, attempts))
+    }
+
+    // Principals locked out at `now`
+    pub fn locked(&self, now: u64) -> Vec<Lockout> {
+        self.attempts
+            .iter()
+            .filter(|(_, attempts)| now < attempts.locked_until)
+            .map(|(principal, attempts)| lockout(principal, attempts))
+            .collect()
+    }
+}
+
+// Whether `attempts` is neither locking its principal out, nor counting
+// towards a lockout or a longer one
+fn is_spent(attempts: &FailedAttempts, now: u64, policy: &LockoutPolicy) -> bool {
+    let window_end = attempts
+        .window_start
+        .saturating_add(seconds(policy.window_seconds));
+    let forgiven_at = attempts
+        .locked_until
+        .saturating_add(seconds(policy.max_lockout_seconds));
+    now >= window_end
+        && now >= attempts.locked_until
+        && (attempts.strikes == 0 || now >= forgiven_at)
+}
+
+fn seconds(seconds: u64) -> u64 {
+    seconds.saturating_mul(NANOS_PER_SECOND)
+}
+
+fn lockout(principal: Principal, attempts: FailedAttempts) -> Lockout {
+    Lockout {
+        principal,
+        failures: attempts.failures,
+        window_start: attempts.window_start,
+        strikes: attempts.strikes,
+        locked_until: attempts.locked_until,
+    }
+}
+
+pub fn validate_policy(policy: &LockoutPolicy) -> Result<(), FaucetError> {
+    if policy.max_failures == 0 || policy.window_seconds == 0 || policy.lockout_seconds == 0 {
+        return Err(FaucetError::InvalidArgument {
+            message: "Lockout failures, window and length must be at least 1".to_string(),
+        });
+    }
+    if policy.max_lockout_seconds < policy.lockout_seconds {
+        return Err(FaucetError::InvalidArgument {
+            message: "Maximum lockout cannot be shorter than the first lockout".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            max_failures: 3,
+            window_seconds: 60,
+            lockout_seconds: 10,
+            max_lockout_seconds: 30,
+        }
+    }
+
+    fn fail(lockouts: &mut Lockouts<VectorMemory>, principal: Principal, now: u64) -> FaucetError {
+        lockouts.record_failure(principal, now, &policy(), FaucetError::InvalidCode)
+    }
+
+    #[test]
+    fn lockouts_back_off_exponentially() {
+        let mut lockouts = Lockouts::init(VectorMemory::default());
+        let principal = Principal::from_slice(&[1]);
+
+        assert_eq!(fail(&mut lockouts, principal, 0), FaucetError::InvalidCode);
+        assert_eq!(fail(&mut lockouts, principal, 0), FaucetError::InvalidCode);
+        let first = FaucetError::LockedOut {
+            locked_until: seconds(10),
+            strikes: 1,
+        };
+        assert_eq!(fail(&mut lockouts, principal, 0), first);
+        assert_eq!(lockouts.check(principal, seconds(9)), Err(first));
+        assert_eq!(lockouts.check(principal, seconds(10)), Ok(()));
+
+        for _ in 0..2 {
+            fail(&mut lockouts, principal, seconds(10));
+        }
+        // Twice as long as the first
+        assert_eq!(
+            fail(&mut lockouts, principal, seconds(10)),
+            FaucetError::LockedOut {
+                locked_until: seconds(30),
+                strikes: 2,
+            }
+        );
+        for _ in 0..2 {
+            fail(&mut lockouts, principal, seconds(30));
+        }
+        // Capped at the maximum
+        assert_eq!(
+            fail(&mut lockouts, principal, seconds(30)),
+            FaucetError::LockedOut {
+                locked_until: seconds(60),
+                strikes: 3,
+            }
+        );
+
+        assert_eq!(lockouts.locked(seconds(59)).len(), 1);
+        assert!(lockouts.clear(principal));
+        assert_eq!(lockouts.check(principal, seconds(59)), Ok(()));
+    }
+
+    #[test]
+    fn failures_outside_the_window_do_not_add_up() {
+        let mut lockouts = Lockouts::init(VectorMemory::default());
+        let principal = Principal::from_slice(&[1]);
+
+        fail(&mut lockouts, principal, 0);
+        fail(&mut lockouts, principal, 0);
+        assert_eq!(
+            fail(&mut lockouts, principal, seconds(60)),
+            FaucetError::InvalidCode
+        );
+        assert_eq!(lockouts.get(principal).unwrap().failures, 1);
+    }
+
+    #[test]
+    fn spent_entries_are_pruned_as_new_failures_come_in() {
+        let mut lockouts = Lockouts::init(VectorMemory::default());
+        for id in 0..20 {
+            fail(&mut lockouts, Principal::from_slice(&[id]), 0);
+        }
+        assert_eq!(lockouts.attempts.len(), 20);
+
+        // A minute later none of the single guesses counts any more
+        for id in 100..110 {
+            fail(&mut lockouts, Principal::from_slice(&[id]), seconds(60));
+        }
+        assert_eq!(lockouts.attempts.len(), 10);
+        assert!(lockouts.get(Principal::from_slice(&[100])).is_some());
+    }
+}
@@ -0,0 +1,1330 @@
+use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::batches::{BatchCode, CodeBatch};
+use crate::campaigns::{DEFAULT_CAMPAIGN_ID, DEFAULT_CAMPAIGN_NAME};
+use crate::codes::{self, DEFAULT_CODE_LABEL};
+use crate::ledger::LedgerKind;
+use crate::lockouts::FailedAttempts;
+use crate::recent::DEFAULT_RECENT_CLAIMS_CAPACITY;
+use crate::state::{
+    self, Config, StoredClaimRecord, CAMPAIGNS, CLAIMED_PRINCIPALS, CLAIMS, CODE_BATCHES,
+    RECENT_CLAIMS,
+};
+use crate::types::{
+    CallerPolicy, Campaign, CampaignCode, CampaignStatus, ClaimMode, ClaimRecord, ClaimStatus,
+    CodeHash, CodeNormalization, DestinationPolicy, JournalEntry, LabeledCode, LockoutPolicy,
+    LowBalanceAlarm, RotatingCode, TransferStatus,
+};
+
+// Every layout faucet state has been persisted in:
+//
+// V1: the whole `State` struct, `stable_save`d by `pre_upgrade`.
+// V2: `Config` in a stable cell, claims in their own stable structures.
+// V3: recent claims move to a bounded, timestamped buffer with a
+//     configurable capacity.
+// V4: claim mode (one-shot or cooldown); the claimed principal set becomes
+//     a map to each principal's latest claim time.
+// V5: campaigns. The faucet settings move out of `Config` into campaign 0
+//     and the claimed principal map is keyed by campaign.
+// V6: code guessing lockout policy.
+// V7: caller policy; anonymous and canister callers are denied by default.
+// V8: destination policy; any account may be paid.
+//
+// Claim records are stored in their own `VersionedClaimRecord` envelope and
+// follow the same rules:
+//
+// V1: id, principal, amount and timestamp. The V2 (principal, amount)
+//     history is drained into these with a timestamp of 0.
+// V2: destination, fee, ledger block, code and status added.
+// V3: campaign id added; earlier claims belong to the default campaign.
+// V4: the submitted code is no longer kept. Earlier records are rewritten
+//     in the latest version at upgrade, so none remains in stable memory.
+// V5: label of the campaign code used added.
+//
+// Campaigns are stored in a `VersionedCampaign` envelope:
+//
+// V1: settings, status, disbursed total and claims epoch.
+// V2: claim limit, claim count and in-flight reservations added.
+// V3: the code is replaced by a salted SHA-256 of it.
+// V4: the code may instead be a rotating (TOTP) code.
+// V5: a set of labelled codes and a normalization policy replace the code.
+// V6: low balance alarm added.
+// V7: extra payouts on other ledgers added.
+//
+// Single-use code batches are stored in a `VersionedCodeBatch` envelope:
+//
+// V1: campaign, salt, size and creation time.
+// V2: the normalization policy the codes were hashed under added.
+// Batches added before the (campaign id, batch id) index are indexed at
+// upgrade.
+//
+// Failed code attempts are stored in a `VersionedFailedAttempts` envelope,
+// currently V1.
+//
+// Payout journal entries are stored in a `VersionedJournalEntry` envelope:
+//
+// V1: claimant, ledger, amount, status and retry bookkeeping.
+// V2: destination account added; earlier entries pay the claimant.
+// V3: extra payouts; earlier entries are main payouts without any.
+//
+// Recent claims are a buffer of claim ids; after any claim migration it is
+// seeded from the tail of the claim log.
+//
+// The config cell always holds a `VersionedConfig`. When `Config` changes
+// shape, freeze its current definition here as `ConfigVN`, point the `VN`
+// variant at it, add a `V{N+1}(Config)` variant and a `From` step for it.
+// Never edit a variant that has been released.
+//
+// Frozen structs never hold the latest `Config`, `Campaign`, `ClaimRecord`
+// or `JournalEntry`, nor a type that has changed shape since they were
+// frozen. They do hold other live types, such as `LockoutPolicy`,
+// `CodeHash`, `CodeNormalization` or `BatchCode`: before changing the shape
+// of one of those, freeze a copy of it here for the structs that hold it,
+// as `CampaignCodeV1` was for `CampaignCode`.
+
+#[derive(CandidType, Deserialize)]
+pub struct StateV1 {
+    pub custodians: HashSet<Principal>,
+    pub is_faucet_enabled: bool,
+    pub faucet_code: String,
+    pub faucet_amount: u64,
+    pub claimed_principals: Vec<Principal>,
+    pub recent_claims: VecDeque<(Principal, u64)>,
+    pub total_claims: Vec<(Principal, u64)>,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: Option<LedgerKind>,
+    pub transfer_fee: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigV2 {
+    pub custodians: HashSet<Principal>,
+    pub is_faucet_enabled: bool,
+    pub faucet_code: String,
+    pub faucet_amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimRecordV1 {
+    pub id: u64,
+    pub principal: Principal,
+    pub amount: u64,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimRecordV2 {
+    pub id: u64,
+    pub principal: Principal,
+    pub destination: Account,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub timestamp: u64,
+    pub ledger: Option<Principal>,
+    pub block_index: Option<Nat>,
+    pub code: Option<String>,
+    pub status: ClaimStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimRecordV3 {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub principal: Principal,
+    pub destination: Account,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub timestamp: u64,
+    pub ledger: Option<Principal>,
+    pub block_index: Option<Nat>,
+    pub code: Option<String>,
+    pub status: ClaimStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimRecordV4 {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub principal: Principal,
+    pub destination: Account,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub timestamp: u64,
+    pub ledger: Option<Principal>,
+    pub block_index: Option<Nat>,
+    pub status: ClaimStatus,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedClaimRecord {
+    V1(ClaimRecordV1),
+    V2(ClaimRecordV2),
+    V3(ClaimRecordV3),
+    V4(ClaimRecordV4),
+    V5(ClaimRecord),
+}
+
+impl VersionedClaimRecord {
+    fn predates_v4(&self) -> bool {
+        matches!(self, Self::V1(_) | Self::V2(_) | Self::V3(_))
+    }
+}
+
+impl From<VersionedClaimRecord> for ClaimRecord {
+    fn from(versioned: VersionedClaimRecord) -> Self {
+        match versioned {
+            VersionedClaimRecord::V1(record) => {
+                ClaimRecordV4::from(ClaimRecordV3::from(ClaimRecordV2::from(record))).into()
+            }
+            VersionedClaimRecord::V2(record) => {
+                ClaimRecordV4::from(ClaimRecordV3::from(record)).into()
+            }
+            VersionedClaimRecord::V3(record) => ClaimRecordV4::from(record).into(),
+            VersionedClaimRecord::V4(record) => record.into(),
+            VersionedClaimRecord::V5(record) => record,
+        }
+    }
+}
+
+impl From<ClaimRecordV4> for ClaimRecord {
+    fn from(record: ClaimRecordV4) -> Self {
+        Self {
+            id: record.id,
+            campaign_id: record.campaign_id,
+            principal: record.principal,
+            destination: record.destination,
+            amount: record.amount,
+            fee: record.fee,
+            timestamp: record.timestamp,
+            ledger: record.ledger,
+            block_index: record.block_index,
+            status: record.status,
+            code_label: None,
+        }
+    }
+}
+
+impl From<ClaimRecordV3> for ClaimRecordV4 {
+    fn from(record: ClaimRecordV3) -> Self {
+        Self {
+            id: record.id,
+            campaign_id: record.campaign_id,
+            principal: record.principal,
+            destination: record.destination,
+            amount: record.amount,
+            fee: record.fee,
+            timestamp: record.timestamp,
+            ledger: record.ledger,
+            block_index: record.block_index,
+            status: record.status,
+        }
+    }
+}
+
+impl From<ClaimRecordV2> for ClaimRecordV3 {
+    fn from(record: ClaimRecordV2) -> Self {
+        Self {
+            id: record.id,
+            campaign_id: DEFAULT_CAMPAIGN_ID,
+            principal: record.principal,
+            destination: record.destination,
+            amount: record.amount,
+            fee: record.fee,
+            timestamp: record.timestamp,
+            ledger: record.ledger,
+            block_index: record.block_index,
+            code: record.code,
+            status: record.status,
+        }
+    }
+}
+
+impl From<ClaimRecordV1> for ClaimRecordV2 {
+    fn from(record: ClaimRecordV1) -> Self {
+        Self {
+            id: record.id,
+            principal: record.principal,
+            destination: record.principal.into(),
+            amount: record.amount,
+            fee: None,
+            timestamp: record.timestamp,
+            ledger: None,
+            block_index: None,
+            code: None,
+            status: ClaimStatus::Legacy,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigV3 {
+    pub custodians: HashSet<Principal>,
+    pub is_faucet_enabled: bool,
+    pub faucet_code: String,
+    pub faucet_amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub recent_claims_capacity: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigV4 {
+    pub custodians: HashSet<Principal>,
+    pub is_faucet_enabled: bool,
+    pub faucet_code: String,
+    pub faucet_amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub recent_claims_capacity: u64,
+    pub claim_mode: ClaimMode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigV5 {
+    pub custodians: HashSet<Principal>,
+    pub recent_claims_capacity: u64,
+    pub legacy_faucet: Option<CampaignV1>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConfigV6 {
+    pub custodians: HashSet<Principal>,
+    pub recent_claims_capacity: u64,
+    pub lockout_policy: LockoutPolicy,
+    pub legacy_faucet: Option<CampaignV5>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConfigV7 {
+    pub custodians: HashSet<Principal>,
+    pub recent_claims_capacity: u64,
+    pub lockout_policy: LockoutPolicy,
+    pub caller_policy: CallerPolicy,
+    pub legacy_faucet: Option<CampaignV5>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedConfig {
+    V2(ConfigV2),
+    V3(ConfigV3),
+    V4(ConfigV4),
+    V5(ConfigV5),
+    V6(ConfigV6),
+    V7(ConfigV7),
+    V8(Config),
+}
+
+impl From<VersionedConfig> for Config {
+    fn from(versioned: VersionedConfig) -> Self {
+        match versioned {
+            VersionedConfig::V2(config) => ConfigV7::from(ConfigV6::from(ConfigV5::from(
+                ConfigV4::from(ConfigV3::from(config)),
+            )))
+            .into(),
+            VersionedConfig::V3(config) => {
+                ConfigV7::from(ConfigV6::from(ConfigV5::from(ConfigV4::from(config)))).into()
+            }
+            VersionedConfig::V4(config) => {
+                ConfigV7::from(ConfigV6::from(ConfigV5::from(config))).into()
+            }
+            VersionedConfig::V5(config) => ConfigV7::from(ConfigV6::from(config)).into(),
+            VersionedConfig::V6(config) => ConfigV7::from(config).into(),
+            VersionedConfig::V7(config) => config.into(),
+            VersionedConfig::V8(config) => config,
+        }
+    }
+}
+
+impl From<ConfigV7> for Config {
+    fn from(config: ConfigV7) -> Self {
+        Self {
+            custodians: config.custodians,
+            recent_claims_capacity: config.recent_claims_capacity,
+            lockout_policy: config.lockout_policy,
+            caller_policy: config.caller_policy,
+            destination_policy: DestinationPolicy::default(),
+            legacy_faucet: config
+                .legacy_faucet
+                .map(|campaign| CampaignV6::from(campaign).into()),
+        }
+    }
+}
+
+impl From<ConfigV6> for ConfigV7 {
+    fn from(config: ConfigV6) -> Self {
+        Self {
+            custodians: config.custodians,
+            recent_claims_capacity: config.recent_claims_capacity,
+            lockout_policy: config.lockout_policy,
+            caller_policy: CallerPolicy::default(),
+            legacy_faucet: config.legacy_faucet,
+        }
+    }
+}
+
+impl From<ConfigV5> for ConfigV6 {
+    fn from(config: ConfigV5) -> Self {
+        Self {
+            custodians: config.custodians,
+            recent_claims_capacity: config.recent_claims_capacity,
+            lockout_policy: LockoutPolicy::default(),
+            legacy_faucet: config.legacy_faucet.map(|campaign| {
+                CampaignV5::from(CampaignV4::from(CampaignV3::from(CampaignV2::from(
+                    campaign,
+                ))))
+            }),
+        }
+    }
+}
+
+impl From<ConfigV4> for ConfigV5 {
+    fn from(config: ConfigV4) -> Self {
+        let status = if config.is_faucet_enabled {
+            CampaignStatus::Active
+        } else {
+            CampaignStatus::Paused
+        };
+        Self {
+            custodians: config.custodians,
+            recent_claims_capacity: config.recent_claims_capacity,
+            legacy_faucet: Some(CampaignV1 {
+                id: DEFAULT_CAMPAIGN_ID,
+                name: DEFAULT_CAMPAIGN_NAME.to_string(),
+                code: config.faucet_code,
+                amount: config.faucet_amount,
+                ledger_canister_id: config.ledger_canister_id,
+                ledger_kind: config.ledger_kind,
+                transfer_fee: config.transfer_fee,
+                claim_mode: config.claim_mode,
+                budget: None,
+                disbursed: 0,
+                start_time: None,
+                end_time: None,
+                status,
+                claims_epoch: 0,
+            }),
+        }
+    }
+}
+
+impl From<ConfigV3> for ConfigV4 {
+    fn from(config: ConfigV3) -> Self {
+        Self {
+            custodians: config.custodians,
+            is_faucet_enabled: config.is_faucet_enabled,
+            faucet_code: config.faucet_code,
+            faucet_amount: config.faucet_amount,
+            ledger_canister_id: config.ledger_canister_id,
+            ledger_kind: config.ledger_kind,
+            transfer_fee: config.transfer_fee,
+            recent_claims_capacity: config.recent_claims_capacity,
+            claim_mode: ClaimMode::OneShot,
+        }
+    }
+}
+
+impl From<ConfigV2> for ConfigV3 {
+    fn from(config: ConfigV2) -> Self {
+        Self {
+            custodians: config.custodians,
+            is_faucet_enabled: config.is_faucet_enabled,
+            faucet_code: config.faucet_code,
+            faucet_amount: config.faucet_amount,
+            ledger_canister_id: config.ledger_canister_id,
+            ledger_kind: config.ledger_kind,
+            transfer_fee: config.transfer_fee,
+            recent_claims_capacity: DEFAULT_RECENT_CLAIMS_CAPACITY,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CampaignV1 {
+    pub id: u64,
+    pub name: String,
+    pub code: String,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub disbursed: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CampaignV2 {
+    pub id: u64,
+    pub name: String,
+    pub code: String,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CampaignV3 {
+    pub id: u64,
+    pub name: String,
+    pub code_hash: Option<CodeHash>,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum CampaignCodeV1 {
+    Hashed(CodeHash),
+    Rotating(RotatingCode),
+}
+
+impl From<CampaignCodeV1> for CampaignCode {
+    fn from(code: CampaignCodeV1) -> Self {
+        match code {
+            CampaignCodeV1::Hashed(code_hash) => Self::Hashed(code_hash),
+            CampaignCodeV1::Rotating(rotating) => Self::Rotating(rotating),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LabeledCodeV1 {
+    pub label: String,
+    pub code: CampaignCodeV1,
+}
+
+impl From<LabeledCodeV1> for LabeledCode {
+    fn from(code: LabeledCodeV1) -> Self {
+        Self {
+            label: code.label,
+            code: code.code.into(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CampaignV4 {
+    pub id: u64,
+    pub name: String,
+    pub code: Option<CampaignCodeV1>,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CampaignV5 {
+    pub id: u64,
+    pub name: String,
+    pub codes: Vec<LabeledCodeV1>,
+    pub normalization: CodeNormalization,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CampaignV6 {
+    pub id: u64,
+    pub name: String,
+    pub codes: Vec<LabeledCodeV1>,
+    pub normalization: CodeNormalization,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+    pub low_balance: Option<LowBalanceAlarm>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedCampaign {
+    V1(CampaignV1),
+    V2(CampaignV2),
+    V3(CampaignV3),
+    V4(CampaignV4),
+    V5(CampaignV5),
+    V6(CampaignV6),
+    V7(Campaign),
+}
+
+impl From<VersionedCampaign> for Campaign {
+    fn from(versioned: VersionedCampaign) -> Self {
+        match versioned {
+            VersionedCampaign::V1(campaign) => CampaignV6::from(CampaignV5::from(
+                CampaignV4::from(CampaignV3::from(CampaignV2::from(campaign))),
+            ))
+            .into(),
+            VersionedCampaign::V2(campaign) => CampaignV6::from(CampaignV5::from(
+                CampaignV4::from(CampaignV3::from(campaign)),
+            ))
+            .into(),
+            VersionedCampaign::V3(campaign) => {
+                CampaignV6::from(CampaignV5::from(CampaignV4::from(campaign))).into()
+            }
+            VersionedCampaign::V4(campaign) => CampaignV6::from(CampaignV5::from(campaign)).into(),
+            VersionedCampaign::V5(campaign) => CampaignV6::from(campaign).into(),
+            VersionedCampaign::V6(campaign) => campaign.into(),
+            VersionedCampaign::V7(campaign) => campaign,
+        }
+    }
+}
+
+impl From<CampaignV6> for Campaign {
+    fn from(campaign: CampaignV6) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            codes: campaign.codes.into_iter().map(LabeledCode::from).collect(),
+            normalization: campaign.normalization,
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            reserved_amount: campaign.reserved_amount,
+            reserved_claims: campaign.reserved_claims,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+            low_balance: campaign.low_balance,
+            extra_payouts: Vec::new(),
+        }
+    }
+}
+
+impl From<CampaignV5> for CampaignV6 {
+    fn from(campaign: CampaignV5) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            codes: campaign.codes,
+            normalization: campaign.normalization,
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            reserved_amount: campaign.reserved_amount,
+            reserved_claims: campaign.reserved_claims,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+            low_balance: None,
+        }
+    }
+}
+
+// The single code becomes the default-labelled one, compared exactly as
+// before
+impl From<CampaignV4> for CampaignV5 {
+    fn from(campaign: CampaignV4) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            codes: campaign
+                .code
+                .map(|code| LabeledCodeV1 {
+                    label: DEFAULT_CODE_LABEL.to_string(),
+                    code,
+                })
+                .into_iter()
+                .collect(),
+            normalization: CodeNormalization::default(),
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            reserved_amount: campaign.reserved_amount,
+            reserved_claims: campaign.reserved_claims,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+        }
+    }
+}
+
+impl From<CampaignV3> for CampaignV4 {
+    fn from(campaign: CampaignV3) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            code: campaign.code_hash.map(CampaignCodeV1::Hashed),
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            reserved_amount: campaign.reserved_amount,
+            reserved_claims: campaign.reserved_claims,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+        }
+    }
+}
+
+// Plaintext codes are hashed with a salt derived from the campaign and the
+// time of the upgrade that migrates them, so it differs between canisters
+impl From<CampaignV2> for CampaignV3 {
+    fn from(campaign: CampaignV2) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            code_hash: Some(CodeHash::new(
+                &campaign.code,
+                codes::salt(campaign.id, UPGRADE_TIME.with(Cell::get)),
+            )),
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: campaign.max_claims,
+            disbursed: campaign.disbursed,
+            claim_count: campaign.claim_count,
+            reserved_amount: campaign.reserved_amount,
+            reserved_claims: campaign.reserved_claims,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+        }
+    }
+}
+
+impl From<CampaignV1> for CampaignV2 {
+    fn from(campaign: CampaignV1) -> Self {
+        Self {
+            id: campaign.id,
+            name: campaign.name,
+            code: campaign.code,
+            amount: campaign.amount,
+            ledger_canister_id: campaign.ledger_canister_id,
+            ledger_kind: campaign.ledger_kind,
+            transfer_fee: campaign.transfer_fee,
+            claim_mode: campaign.claim_mode,
+            budget: campaign.budget,
+            max_claims: None,
+            disbursed: campaign.disbursed,
+            claim_count: 0,
+            reserved_amount: 0,
+            reserved_claims: 0,
+            start_time: campaign.start_time,
+            end_time: campaign.end_time,
+            status: campaign.status,
+            claims_epoch: campaign.claims_epoch,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CodeBatchV1 {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub salt: Vec<u8>,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedCodeBatch {
+    V1(CodeBatchV1),
+    V2(CodeBatch),
+}
+
+impl From<VersionedCodeBatch> for CodeBatch {
+    fn from(versioned: VersionedCodeBatch) -> Self {
+        match versioned {
+            VersionedCodeBatch::V1(batch) => batch.into(),
+            VersionedCodeBatch::V2(batch) => batch,
+        }
+    }
+}
+
+// Codes were hashed exactly as given
+impl From<CodeBatchV1> for CodeBatch {
+    fn from(batch: CodeBatchV1) -> Self {
+        Self {
+            id: batch.id,
+            campaign_id: batch.campaign_id,
+            salt: batch.salt,
+            normalization: CodeNormalization::default(),
+            size: batch.size,
+            created_at: batch.created_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedFailedAttempts {
+    V1(FailedAttempts),
+}
+
+impl From<VersionedFailedAttempts> for FailedAttempts {
+    fn from(versioned: VersionedFailedAttempts) -> Self {
+        match versioned {
+            VersionedFailedAttempts::V1(attempts) => attempts,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferStatusV1 {
+    Pending,
+    Succeeded { block_index: Nat, claim_id: u64 },
+    Failed { message: String },
+}
+
+impl From<TransferStatusV1> for TransferStatus {
+    fn from(status: TransferStatusV1) -> Self {
+        match status {
+            TransferStatusV1::Pending => Self::Pending,
+            TransferStatusV1::Succeeded {
+                block_index,
+                claim_id,
+            } => Self::Succeeded {
+                block_index,
+                claim_id,
+            },
+            TransferStatusV1::Failed { message } => Self::Failed { message },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntryV1 {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub principal: Principal,
+    pub ledger: Principal,
+    pub ledger_kind: LedgerKind,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub created_at_time: u64,
+    pub code_label: Option<String>,
+    pub batch_code: Option<BatchCode>,
+    pub status: TransferStatusV1,
+    pub attempts: u64,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntryV2 {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub principal: Principal,
+    pub destination: Account,
+    pub ledger: Principal,
+    pub ledger_kind: LedgerKind,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub created_at_time: u64,
+    pub code_label: Option<String>,
+    pub batch_code: Option<BatchCode>,
+    pub status: TransferStatusV1,
+    pub attempts: u64,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum VersionedJournalEntry {
+    V1(JournalEntryV1),
+    V2(JournalEntryV2),
+    V3(JournalEntry),
+}
+
+impl From<VersionedJournalEntry> for JournalEntry {
+    fn from(versioned: VersionedJournalEntry) -> Self {
+        match versioned {
+            VersionedJournalEntry::V1(entry) => JournalEntryV2::from(entry).into(),
+            VersionedJournalEntry::V2(entry) => entry.into(),
+            VersionedJournalEntry::V3(entry) => entry,
+        }
+    }
+}
+
+impl From<JournalEntryV2> for JournalEntry {
+    fn from(entry: JournalEntryV2) -> Self {
+        Self {
+            id: entry.id,
+            campaign_id: entry.campaign_id,
+            principal: entry.principal,
+            destination: entry.destination,
+            ledger: entry.ledger,
+            ledger_kind: entry.ledger_kind,
+            amount: entry.amount,
+            fee: entry.fee,
+            created_at_time: entry.created_at_time,
+            code_label: entry.code_label,
+            batch_code: entry.batch_code,
+            status: entry.status.into(),
+            attempts: entry.attempts,
+            last_error: entry.last_error,
+            updated_at: entry.updated_at,
+            main_payout: None,
+            extra_payouts: Vec::new(),
+        }
+    }
+}
+
+impl From<JournalEntryV1> for JournalEntryV2 {
+    fn from(entry: JournalEntryV1) -> Self {
+        Self {
+            id: entry.id,
+            campaign_id: entry.campaign_id,
+            principal: entry.principal,
+            destination: Account::from(entry.principal),
+            ledger: entry.ledger,
+            ledger_kind: entry.ledger_kind,
+            amount: entry.amount,
+            fee: entry.fee,
+            created_at_time: entry.created_at_time,
+            code_label: entry.code_label,
+            batch_code: entry.batch_code,
+            status: entry.status,
+            attempts: entry.attempts,
+            last_error: entry.last_error,
+            updated_at: entry.updated_at,
+        }
+    }
+}
+
+thread_local! {
+    // When the upgrade being migrated ran, as entropy for salting codes
+    static UPGRADE_TIME: Cell<u64> = const { Cell::new(0) };
+}
+
+// Brings whatever stable memory holds up to the latest layout
+pub fn run() {
+    UPGRADE_TIME.with(|time| time.set(ic_cdk::api::time()));
+    let v1_state = state::take_v1_state();
+    state::rewrite_legacy_claim_records();
+    if let Some(state) = v1_state {
+        migrate_v1(state);
+    }
+    migrate_legacy_faucet();
+    rewrite_campaigns();
+    migrate_total_claims(state::take_v2_total_claims());
+    migrate_claimed_principals(with_last_claim_times(state::take_v2_claimed_principals()));
+    migrate_claimed_principals(state::take_v4_claimed_principals());
+    seed_recent_claims();
+    CODE_BATCHES.with(|batches| batches.borrow_mut().index_campaigns());
+    // Decoding the config cell migrates it; write it back in the latest
+    // version so the migration only runs once
+    state::mutate_config(|_| ());
+}
+
+// Records are written in id order, each in the version current at the time,
+// so those from before V4 come first
+pub fn rewrite_legacy_claim_records<M: Memory>(
+    records: &mut StableBTreeMap<u64, StoredClaimRecord, M>,
+) {
+    let legacy: Vec<u64> = records
+        .iter()
+        .take_while(|(_, record)| record.0.predates_v4())
+        .map(|(id, _)| id)
+        .collect();
+    for id in legacy {
+        let record = records.get(&id).expect("Listed above").0;
+        records.insert(
+            id,
+            StoredClaimRecord(VersionedClaimRecord::V5(record.into())),
+        );
+    }
+}
+
+// Decodes a V1 blob the same way `stable_restore` did
+pub fn decode_v1(bytes: &[u8]) -> Result<StateV1, String> {
+    let mut de = candid::de::IDLDeserialize::new(bytes).map_err(|e| format!("{:?}", e))?;
+    de.get_value::<StateV1>().map_err(|e| format!("{:?}", e))
+}
+
+// V1 -> V2: split the heap state into the config cell and claim structures
+fn migrate_v1(state: StateV1) {
+    let config = ConfigV2 {
+        custodians: state.custodians,
+        is_faucet_enabled: state.is_faucet_enabled,
+        faucet_code: state.faucet_code,
+        faucet_amount: state.faucet_amount,
+        ledger_canister_id: state.ledger_canister_id,
+        ledger_kind: state.ledger_kind.unwrap_or_default(),
+        transfer_fee: state.transfer_fee,
+    };
+    state::mutate_config(|latest| {
+        *latest = ConfigV7::from(ConfigV6::from(ConfigV5::from(ConfigV4::from(
+            ConfigV3::from(config),
+        ))))
+        .into()
+    });
+    migrate_legacy_faucet();
+    rewrite_campaigns();
+    migrate_total_claims(state.total_claims);
+    migrate_claimed_principals(with_last_claim_times(state.claimed_principals));
+}
+
+// V4 -> V5: the faucet settings become the default campaign
+fn migrate_legacy_faucet() {
+    if let Some(campaign) = state::mutate_config(|config| config.legacy_faucet.take()) {
+        CAMPAIGNS.with(|campaigns| campaigns.borrow_mut().insert(campaign));
+    }
+}
+
+// Decoding a campaign migrates it; campaigns are few, so all are written
+// back in the latest version and none keeps a plaintext code
+fn rewrite_campaigns() {
+    CAMPAIGNS.with(|campaigns| {
+        let mut campaigns = campaigns.borrow_mut();
+        for campaign in campaigns.list() {
+            campaigns.insert(campaign);
+        }
+    });
+}
+
+// V2 claim tuples -> timestamp-less claim records
+fn migrate_total_claims(claims: Vec<(Principal, u64)>) {
+    CLAIMS.with(|log| {
+        let mut log = log.borrow_mut();
+        for (principal, amount) in claims {
+            let id = log.next_id();
+            let record = ClaimRecordV2::from(ClaimRecordV1 {
+                id,
+                principal,
+                amount,
+                timestamp: 0,
+            });
+            log.insert(ClaimRecordV4::from(ClaimRecordV3::from(record)).into());
+        }
+    });
+}
+
+// The V2 claimed set only held principals; take each one's latest claim
+// time from the claim log, or 0 if it has none
+fn with_last_claim_times(principals: Vec<Principal>) -> Vec<(Principal, u64)> {
+    principals
+        .into_iter()
+        .map(|principal| {
+            let last_claim = CLAIMS
+                .with(|log| log.borrow().last_for(principal))
+                .map_or(0, |record| record.timestamp);
+            (principal, last_claim)
+        })
+        .collect()
+}
+
+// Claimed principals from before campaigns claimed from the default one
+fn migrate_claimed_principals(principals: Vec<(Principal, u64)>) {
+    if principals.is_empty() {
+        return;
+    }
+    let campaign = CAMPAIGNS
+        .with(|campaigns| campaigns.borrow().get(DEFAULT_CAMPAIGN_ID))
+        .unwrap_or_default();
+    CLAIMED_PRINCIPALS.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        for (principal, last_claim) in principals {
+            registry.insert(&campaign, principal, last_claim);
+        }
+    });
+}
+
+// Recent claims that predate the id buffer are the tail of the claim log
+fn seed_recent_claims() {
+    if RECENT_CLAIMS.with(|recent| recent.borrow().len()) > 0 {
+        return;
+    }
+    let capacity = state::read_config(|config| config.recent_claims_capacity);
+    let next_id = CLAIMS.with(|log| log.borrow().next_id());
+    RECENT_CLAIMS.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        for claim_id in next_id.saturating_sub(capacity)..next_id {
+            recent.push(claim_id, capacity);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FaucetError;
+    use candid::Encode;
+    use ic_stable_structures::{Storable, VectorMemory};
+    use std::borrow::Cow;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn v1_state_restores_into_latest() {
+        let v1 = StateV1 {
+            custodians: HashSet::from([principal(1)]),
+            is_faucet_enabled: true,
+            faucet_code: "windoge".to_string(),
+            faucet_amount: 100,
+            claimed_principals: vec![principal(2), principal(3)],
+            recent_claims: VecDeque::from([(principal(2), 100), (principal(3), 100)]),
+            total_claims: vec![(principal(2), 100), (principal(3), 100)],
+            ledger_canister_id: Some(principal(9)),
+            ledger_kind: None,
+            transfer_fee: Some(10),
+        };
+        // `stable_save` wrote the args at offset 0 of page-sized memory
+        let mut bytes = candid::encode_args((v1,)).unwrap();
+        bytes.resize(65536, 0);
+
+        migrate_v1(decode_v1(&bytes).unwrap());
+        seed_recent_claims();
+
+        state::read_config(|config| {
+            assert_eq!(config.custodians, HashSet::from([principal(1)]));
+            assert_eq!(config.legacy_faucet, None);
+        });
+        let campaign = CAMPAIGNS
+            .with(|campaigns| campaigns.borrow().get(DEFAULT_CAMPAIGN_ID))
+            .unwrap();
+        assert_eq!(campaign.status, CampaignStatus::Active);
+        assert_eq!(
+            campaign.check_code("windoge", 0),
+            Ok(DEFAULT_CODE_LABEL.to_string())
+        );
+        assert_eq!(campaign.amount, 100);
+        assert_eq!(campaign.ledger_canister_id, Some(principal(9)));
+        assert_eq!(campaign.ledger_kind, LedgerKind::Icrc1);
+        assert_eq!(campaign.transfer_fee, Some(10));
+        assert_eq!(campaign.claim_mode, ClaimMode::OneShot);
+        CLAIMED_PRINCIPALS.with(|principals| {
+            let principals = principals.borrow();
+            let check = |id| principals.check(&campaign, principal(id), 0);
+            assert_eq!(check(2), Err(FaucetError::AlreadyClaimed));
+            assert_eq!(check(3), Err(FaucetError::AlreadyClaimed));
+            assert_eq!(check(4), Ok(()));
+        });
+        RECENT_CLAIMS.with(|claims| assert_eq!(claims.borrow().page(0, 10), vec![1, 0]));
+        CLAIMS.with(|claims| {
+            let claims = claims.borrow();
+            let migrated = claims.for_principal(principal(3));
+            assert_eq!(claims.len(), 2);
+            assert_eq!(migrated.len(), 1);
+            assert_eq!(migrated[0].id, 1);
+            assert_eq!(migrated[0].amount, 100);
+            assert_eq!(migrated[0].destination, principal(3).into());
+            assert_eq!(migrated[0].status, ClaimStatus::Legacy);
+        });
+    }
+
+    #[test]
+    fn v2_config_restores_into_latest() {
+        let v2 = ConfigV2 {
+            custodians: HashSet::from([principal(1)]),
+            is_faucet_enabled: true,
+            faucet_code: "windoge".to_string(),
+            faucet_amount: 100,
+            ledger_canister_id: Some(principal(9)),
+            ledger_kind: LedgerKind::IcpLegacy,
+            transfer_fee: None,
+        };
+        let bytes = Encode!(&VersionedConfig::V2(v2.clone())).unwrap();
+
+        let config = Config::from_bytes(Cow::Owned(bytes));
+
+        let campaign = config.legacy_faucet.unwrap();
+        assert_eq!(config.custodians, v2.custodians);
+        assert_eq!(
+            campaign.check_code(&v2.faucet_code, 0),
+            Ok(DEFAULT_CODE_LABEL.to_string())
+        );
+        assert_eq!(campaign.ledger_kind, LedgerKind::IcpLegacy);
+        assert_eq!(campaign.claim_mode, ClaimMode::OneShot);
+        assert_eq!(
+            config.recent_claims_capacity,
+            DEFAULT_RECENT_CLAIMS_CAPACITY
+        );
+    }
+
+    #[test]
+    fn v1_claim_record_restores_into_latest() {
+        let v1 = ClaimRecordV1 {
+            id: 7,
+            principal: principal(2),
+            amount: 100,
+            timestamp: 42,
+        };
+        let bytes = Encode!(&VersionedClaimRecord::V1(v1)).unwrap();
+
+        let record = ClaimRecord::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(record.id, 7);
+        assert_eq!(record.campaign_id, DEFAULT_CAMPAIGN_ID);
+        assert_eq!(record.timestamp, 42);
+        assert_eq!(record.destination, principal(2).into());
+        assert_eq!(record.block_index, None);
+        assert_eq!(record.status, ClaimStatus::Legacy);
+    }
+
+    #[test]
+    fn legacy_claim_records_are_rewritten_without_their_code() {
+        let mut records = StableBTreeMap::init(VectorMemory::default());
+        let v1 = ClaimRecordV1 {
+            id: 0,
+            principal: principal(2),
+            amount: 100,
+            timestamp: 42,
+        };
+        let v2 = ClaimRecordV2 {
+            code: Some("windoge".to_string()),
+            ..ClaimRecordV2::from(ClaimRecordV1 {
+                id: 1,
+                ..v1.clone()
+            })
+        };
+        records.insert(0, StoredClaimRecord(VersionedClaimRecord::V1(v1)));
+        records.insert(1, StoredClaimRecord(VersionedClaimRecord::V2(v2)));
+        let latest = ClaimRecord {
+            id: 2,
+            ..ClaimRecord::from(records.get(&1).unwrap().0)
+        };
+        records.insert(
+            2,
+            StoredClaimRecord(VersionedClaimRecord::V5(latest.clone())),
+        );
+
+        rewrite_legacy_claim_records(&mut records);
+
+        for (id, record) in records.iter() {
+            match record.0 {
+                VersionedClaimRecord::V5(record) => assert_eq!(record.id, id),
+                _ => panic!("Record {id} was not rewritten"),
+            }
+        }
+        assert_eq!(ClaimRecord::from(records.get(&2).unwrap().0), latest);
+    }
+
+    #[test]
+    fn migrated_codes_are_salted_per_upgrade() {
+        let v2 = CampaignV2 {
+            id: 0,
+            name: DEFAULT_CAMPAIGN_NAME.to_string(),
+            code: "windoge".to_string(),
+            amount: 100,
+            ledger_canister_id: None,
+            ledger_kind: LedgerKind::Icrc1,
+            transfer_fee: None,
+            claim_mode: ClaimMode::OneShot,
+            budget: None,
+            max_claims: None,
+            disbursed: 0,
+            claim_count: 0,
+            reserved_amount: 0,
+            reserved_claims: 0,
+            start_time: None,
+            end_time: None,
+            status: CampaignStatus::Active,
+            claims_epoch: 0,
+        };
+
+        UPGRADE_TIME.with(|time| time.set(1));
+        let first = CampaignV3::from(v2.clone()).code_hash.unwrap();
+        UPGRADE_TIME.with(|time| time.set(2));
+        let second = CampaignV3::from(v2).code_hash.unwrap();
+
+        assert_ne!(first.salt, second.salt);
+        assert!(first.matches("windoge") && second.matches("windoge"));
+    }
+}
@@ -0,0 +1,71 @@
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+pub const DEFAULT_RECENT_CLAIMS_CAPACITY: u64 = 100;
+pub const MAX_RECENT_CLAIMS_CAPACITY: u64 = 10_000;
+
+// Fixed-capacity buffer of the latest claim ids; the records themselves live
+// in the claim log. Entries are keyed by a monotonically increasing sequence
+// number; once the buffer is full each push evicts the oldest entry.
+pub struct RecentClaims<M: Memory> {
+    entries: StableBTreeMap<u64, u64, M>,
+}
+
+impl<M: Memory> RecentClaims<M> {
+    pub fn init(memory: M) -> Self {
+        Self {
+            entries: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn push(&mut self, claim_id: u64, capacity: u64) {
+        let next = self
+            .entries
+            .last_key_value()
+            .map_or(0, |(sequence, _)| sequence + 1);
+        self.entries.insert(next, claim_id);
+        self.truncate(capacity);
+    }
+
+    // Evicts the oldest entries until at most `capacity` remain
+    pub fn truncate(&mut self, capacity: u64) {
+        while self.entries.len() > capacity {
+            self.entries.pop_first();
+        }
+    }
+
+    // Newest first
+    pub fn page(&self, offset: u64, limit: u64) -> Vec<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, claim_id)| claim_id)
+            .collect()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    #[test]
+    fn push_evicts_oldest_beyond_capacity() {
+        let mut recent = RecentClaims::init(VectorMemory::default());
+        for claim_id in 0..5 {
+            recent.push(claim_id, 3);
+        }
+
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent.page(0, 10), vec![4, 3, 2]);
+        assert_eq!(recent.page(1, 1), vec![3]);
+
+        recent.truncate(1);
+        assert_eq!(recent.page(0, 10), vec![4]);
+    }
+}
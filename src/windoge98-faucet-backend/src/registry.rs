@@ -0,0 +1,187 @@
+use candid::Principal;
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::types::{Campaign, ClaimMode, FaucetError, NANOS_PER_SECOND};
+
+// Principals that have claimed from each campaign, with the campaign's
+// claims epoch at the time and their latest claim time. Lookups and inserts
+// walk a stable B-tree (O(log n)). Resetting a campaign bumps its epoch,
+// which is O(1): entries from earlier epochs are ignored and overwritten on
+// the principal's next claim.
+pub struct ClaimedRegistry<M: Memory> {
+    principals: StableBTreeMap<(u64, Principal), (u64, u64), M>,
+}
+
+impl<M: Memory> ClaimedRegistry<M> {
+    pub fn init(memory: M) -> Self {
+        Self {
+            principals: StableBTreeMap::init(memory),
+        }
+    }
+
+    fn last_claim(&self, campaign: &Campaign, principal: Principal) -> Option<u64> {
+        self.principals
+            .get(&(campaign.id, principal))
+            .filter(|(epoch, _)| *epoch == campaign.claims_epoch)
+            .map(|(_, last_claim)| last_claim)
+    }
+
+    // Returns false if the principal had already claimed this epoch
+    pub fn insert(&mut self, campaign: &Campaign, principal: Principal, timestamp: u64) -> bool {
+        let previous = self.last_claim(campaign, principal);
+        self.principals
+            .insert((campaign.id, principal), (campaign.claims_epoch, timestamp));
+        previous.is_none()
+    }
+
+    // Whether `principal` may claim from `campaign` at `now`
+    pub fn check(
+        &self,
+        campaign: &Campaign,
+        principal: Principal,
+        now: u64,
+    ) -> Result<(), FaucetError> {
+        let Some(last_claim) = self.last_claim(campaign, principal) else {
+            return Ok(());
+        };
+        match campaign.claim_mode {
+            ClaimMode::OneShot => Err(FaucetError::AlreadyClaimed),
+            ClaimMode::Cooldown { seconds } => {
+                let next_eligible_at =
+                    last_claim.saturating_add(seconds.saturating_mul(NANOS_PER_SECOND));
+                if now < next_eligible_at {
+                    Err(FaucetError::CooldownActive { next_eligible_at })
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Counts stable memory reads, a deterministic stand-in for the
+    // instructions a lookup costs on the IC
+    #[derive(Clone, Default)]
+    struct CountingMemory {
+        inner: VectorMemory,
+        reads: Rc<Cell<u64>>,
+    }
+
+    impl Memory for CountingMemory {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn grow(&self, pages: u64) -> i64 {
+            self.inner.grow(pages)
+        }
+
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.read(offset, dst)
+        }
+
+        fn write(&self, offset: u64, src: &[u8]) {
+            self.inner.write(offset, src)
+        }
+    }
+
+    fn principal(id: u32) -> Principal {
+        Principal::from_slice(&id.to_be_bytes())
+    }
+
+    fn campaign(id: u64, claim_mode: ClaimMode) -> Campaign {
+        Campaign {
+            id,
+            claim_mode,
+            ..Default::default()
+        }
+    }
+
+    // Memory reads for one claim's check-then-record against a registry
+    // that already holds `size` principals
+    fn reads_per_claim(size: u32) -> u64 {
+        let campaign = campaign(0, ClaimMode::OneShot);
+        let memory = CountingMemory::default();
+        let mut registry = ClaimedRegistry::init(memory.clone());
+        for id in 0..size {
+            registry.insert(&campaign, principal(id), 0);
+        }
+
+        memory.reads.set(0);
+        let newcomer = principal(u32::MAX);
+        assert!(registry.check(&campaign, newcomer, 0).is_ok());
+        assert!(registry.insert(&campaign, newcomer, 0));
+        memory.reads.get()
+    }
+
+    #[test]
+    fn claim_cost_stays_flat_at_100k_principals() {
+        let small = reads_per_claim(1_000);
+        let large = reads_per_claim(100_000);
+        // A linear scan would grow 100x here; the B-tree adds a few levels
+        assert!(
+            large <= small * 3,
+            "claim check took {} reads at 100k principals vs {} at 1k",
+            large,
+            small
+        );
+    }
+
+    #[test]
+    fn new_epoch_forgets_every_principal() {
+        let mut campaign = campaign(0, ClaimMode::OneShot);
+        let mut registry = ClaimedRegistry::init(VectorMemory::default());
+        registry.insert(&campaign, principal(1), 0);
+        registry.insert(&campaign, principal(2), 0);
+
+        campaign.claims_epoch += 1;
+
+        assert!(registry.check(&campaign, principal(1), 0).is_ok());
+        assert!(registry.check(&campaign, principal(2), 0).is_ok());
+        assert!(registry.insert(&campaign, principal(1), 0));
+        assert!(!registry.insert(&campaign, principal(1), 0));
+    }
+
+    #[test]
+    fn campaigns_keep_separate_claimed_sets() {
+        let first = campaign(0, ClaimMode::OneShot);
+        let second = campaign(1, ClaimMode::OneShot);
+        let mut registry = ClaimedRegistry::init(VectorMemory::default());
+        registry.insert(&first, principal(1), 0);
+
+        assert_eq!(
+            registry.check(&first, principal(1), 0),
+            Err(FaucetError::AlreadyClaimed)
+        );
+        assert_eq!(registry.check(&second, principal(1), 0), Ok(()));
+    }
+
+    #[test]
+    fn cooldown_mode_allows_claims_after_the_interval() {
+        let day = campaign(0, ClaimMode::Cooldown { seconds: 86_400 });
+        let one_shot = campaign(0, ClaimMode::OneShot);
+        let mut registry = ClaimedRegistry::init(VectorMemory::default());
+        let claimed_at = 1_000;
+        let next_eligible_at = claimed_at + 86_400 * NANOS_PER_SECOND;
+        registry.insert(&day, principal(1), claimed_at);
+
+        assert_eq!(
+            registry.check(&day, principal(1), next_eligible_at - 1),
+            Err(FaucetError::CooldownActive { next_eligible_at })
+        );
+        assert_eq!(registry.check(&day, principal(1), next_eligible_at), Ok(()));
+        assert_eq!(
+            registry.check(&one_shot, principal(1), next_eligible_at),
+            Err(FaucetError::AlreadyClaimed)
+        );
+        assert_eq!(registry.check(&day, principal(2), claimed_at), Ok(()));
+    }
+}
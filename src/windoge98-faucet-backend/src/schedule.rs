@@ -0,0 +1,132 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use crate::state::{self, SCHEDULE};
+use crate::types::{CampaignStatus, ScheduledToggle};
+
+// Custodian-scheduled campaign toggles, keyed by id, each holding
+// (time in nanoseconds since the epoch, campaign id, is_enabled). Schedules
+// are few, so finding the due ones scans them all.
+pub struct Schedule<M: Memory> {
+    toggles: StableBTreeMap<u64, (u64, u64, bool), M>,
+}
+
+impl<M: Memory> Schedule<M> {
+    pub fn init(memory: M) -> Self {
+        Self {
+            toggles: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn insert(&mut self, at: u64, campaign_id: u64, is_enabled: bool) -> u64 {
+        let id = self.toggles.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.toggles.insert(id, (at, campaign_id, is_enabled));
+        id
+    }
+
+    pub fn remove(&mut self, id: u64) -> bool {
+        self.toggles.remove(&id).is_some()
+    }
+
+    // Soonest first
+    pub fn list(&self) -> Vec<ScheduledToggle> {
+        let mut toggles: Vec<_> = self
+            .toggles
+            .iter()
+            .map(|(id, (at, campaign_id, is_enabled))| ScheduledToggle {
+                id,
+                campaign_id,
+                at,
+                is_enabled,
+            })
+            .collect();
+        toggles.sort_by_key(|toggle| (toggle.at, toggle.id));
+        toggles
+    }
+
+    pub fn next_at(&self) -> Option<u64> {
+        self.toggles.iter().map(|(_, (at, _, _))| at).min()
+    }
+
+    // Removes and returns the toggles due at `now`, soonest first
+    pub fn take_due(&mut self, now: u64) -> Vec<ScheduledToggle> {
+        let due: Vec<_> = self
+            .list()
+            .into_iter()
+            .filter(|toggle| toggle.at <= now)
+            .collect();
+        for toggle in &due {
+            self.toggles.remove(&toggle.id);
+        }
+        due
+    }
+}
+
+thread_local! {
+    // Timers do not survive upgrades, so this only lives on the heap and
+    // `rearm` runs again in `post_upgrade`
+    static TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+// Arms a single timer for the soonest scheduled toggle, replacing any
+// previous one
+pub fn rearm() {
+    if let Some(timer) = TIMER.with(|timer| timer.take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let Some(next_at) = SCHEDULE.with(|schedule| schedule.borrow().next_at()) else {
+        return;
+    };
+    let delay = Duration::from_nanos(next_at.saturating_sub(ic_cdk::api::time()));
+    let timer = ic_cdk_timers::set_timer(delay, run_due);
+    TIMER.with(|cell| cell.set(Some(timer)));
+}
+
+fn run_due() {
+    TIMER.with(|timer| timer.set(None));
+    let due = SCHEDULE.with(|schedule| schedule.borrow_mut().take_due(ic_cdk::api::time()));
+    for toggle in due {
+        let status = if toggle.is_enabled {
+            CampaignStatus::Active
+        } else {
+            CampaignStatus::Paused
+        };
+        // A campaign closed in the meantime stays closed
+        let _ = state::mutate_campaign(toggle.campaign_id, |campaign| {
+            campaign.require_not_closed()?;
+            campaign.status = status;
+            Ok(())
+        });
+    }
+    rearm();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    #[test]
+    fn take_due_returns_toggles_in_time_order() {
+        let mut schedule = Schedule::init(VectorMemory::default());
+        let close = schedule.insert(200, 0, false);
+        let open = schedule.insert(100, 0, true);
+        let later = schedule.insert(300, 1, true);
+
+        assert_eq!(schedule.next_at(), Some(100));
+        let due: Vec<_> = schedule
+            .take_due(200)
+            .into_iter()
+            .map(|toggle| toggle.id)
+            .collect();
+        assert_eq!(due, vec![open, close]);
+        assert_eq!(schedule.next_at(), Some(300));
+
+        assert!(schedule.remove(later));
+        assert_eq!(schedule.next_at(), None);
+        assert!(!schedule.remove(later));
+    }
+}
@@ -0,0 +1,326 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Memory as _;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+
+use crate::batches::{CodeBatch, CodeBatches};
+use crate::campaigns::Campaigns;
+use crate::claims::ClaimLog;
+use crate::journal::Journal;
+use crate::lockouts::{FailedAttempts, Lockouts};
+use crate::migrations::{
+    self, StateV1, VersionedCampaign, VersionedClaimRecord, VersionedCodeBatch, VersionedConfig,
+    VersionedFailedAttempts, VersionedJournalEntry,
+};
+use crate::recent::{RecentClaims, DEFAULT_RECENT_CLAIMS_CAPACITY};
+use crate::registry::ClaimedRegistry;
+use crate::schedule::Schedule;
+use crate::types::{
+    CallerPolicy, Campaign, ClaimRecord, DestinationPolicy, FaucetError, JournalEntry,
+    LockoutPolicy,
+};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Each stable structure lives in its own virtual memory. Never reuse an ID.
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(0);
+// Retired (1): the V2 claimed principal set, drained on upgrade
+// Retired (2): the unbounded V2 recent claims list
+// Retired (3): the V2 (principal, amount) claim history, drained on upgrade
+// Retired (4): recent claims stored by value rather than by claim id
+// Retired (8): the V4 claimed principal map, drained on upgrade
+const CLAIMED_PRINCIPALS_V2_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TOTAL_CLAIMS_V2_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CLAIMS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CLAIMS_BY_PRINCIPAL_MEMORY_ID: MemoryId = MemoryId::new(6);
+const RECENT_CLAIMS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const CLAIMED_PRINCIPALS_V4_MEMORY_ID: MemoryId = MemoryId::new(8);
+const CLAIMED_PRINCIPALS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const CAMPAIGNS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const SCHEDULE_MEMORY_ID: MemoryId = MemoryId::new(11);
+const CODE_BATCHES_MEMORY_ID: MemoryId = MemoryId::new(12);
+const BATCH_CODES_MEMORY_ID: MemoryId = MemoryId::new(13);
+const REDEMPTIONS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const LOCKOUTS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const JOURNAL_MEMORY_ID: MemoryId = MemoryId::new(16);
+const JOURNAL_PENDING_MEMORY_ID: MemoryId = MemoryId::new(17);
+const CAMPAIGN_BATCHES_MEMORY_ID: MemoryId = MemoryId::new(18);
+
+// Faucet configuration (small, rewritten as a whole on every change)
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub custodians: HashSet<Principal>,
+    pub recent_claims_capacity: u64,
+    pub lockout_policy: LockoutPolicy,
+    pub caller_policy: CallerPolicy,
+    pub destination_policy: DestinationPolicy,
+    // The pre-campaign faucet settings of a V4 config, until
+    // `migrations::run` moves them into the default campaign
+    pub legacy_faucet: Option<Campaign>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            custodians: HashSet::new(),
+            recent_claims_capacity: DEFAULT_RECENT_CLAIMS_CAPACITY,
+            lockout_policy: LockoutPolicy::default(),
+            caller_policy: CallerPolicy::default(),
+            destination_policy: DestinationPolicy::default(),
+            legacy_faucet: None,
+        }
+    }
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedConfig::V8(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedConfig)
+            .expect("Failed to decode config")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ClaimRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedClaimRecord::V5(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedClaimRecord)
+            .expect("Failed to decode claim record")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Campaign {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedCampaign::V7(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedCampaign)
+            .expect("Failed to decode campaign")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for CodeBatch {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedCodeBatch::V2(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedCodeBatch)
+            .expect("Failed to decode code batch")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for FailedAttempts {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedFailedAttempts::V1(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedFailedAttempts)
+            .expect("Failed to decode failed attempts")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedJournalEntry::V3(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedJournalEntry)
+            .expect("Failed to decode journal entry")
+            .into()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A claim record as written, without migrating it to the latest version
+pub struct StoredClaimRecord(pub VersionedClaimRecord);
+
+impl Storable for StoredClaimRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Decode!(bytes.as_ref(), VersionedClaimRecord).expect("Failed to decode claim record"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Globals: thread_local!
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(memory(CONFIG_MEMORY_ID), Config::default())
+            .expect("Failed to initialize config cell"),
+    );
+
+    pub static CLAIMED_PRINCIPALS: RefCell<ClaimedRegistry<Memory>> =
+        RefCell::new(ClaimedRegistry::init(memory(CLAIMED_PRINCIPALS_MEMORY_ID)));
+
+    pub static CAMPAIGNS: RefCell<Campaigns<Memory>> =
+        RefCell::new(Campaigns::init(memory(CAMPAIGNS_MEMORY_ID)));
+
+    pub static SCHEDULE: RefCell<Schedule<Memory>> =
+        RefCell::new(Schedule::init(memory(SCHEDULE_MEMORY_ID)));
+
+    pub static CODE_BATCHES: RefCell<CodeBatches<Memory>> = RefCell::new(CodeBatches::init(
+        memory(CODE_BATCHES_MEMORY_ID),
+        memory(CAMPAIGN_BATCHES_MEMORY_ID),
+        memory(BATCH_CODES_MEMORY_ID),
+        memory(REDEMPTIONS_MEMORY_ID),
+    ));
+
+    pub static LOCKOUTS: RefCell<Lockouts<Memory>> =
+        RefCell::new(Lockouts::init(memory(LOCKOUTS_MEMORY_ID)));
+
+    pub static JOURNAL: RefCell<Journal<Memory>> = RefCell::new(Journal::init(
+        memory(JOURNAL_MEMORY_ID),
+        memory(JOURNAL_PENDING_MEMORY_ID),
+    ));
+
+    pub static RECENT_CLAIMS: RefCell<RecentClaims<Memory>> =
+        RefCell::new(RecentClaims::init(memory(RECENT_CLAIMS_MEMORY_ID)));
+
+    pub static CLAIMS: RefCell<ClaimLog<Memory>> = RefCell::new(ClaimLog::init(
+        memory(CLAIMS_MEMORY_ID),
+        memory(CLAIMS_BY_PRINCIPAL_MEMORY_ID),
+    ));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+pub fn read_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with(|config| f(config.borrow().get()))
+}
+
+pub fn mutate_config<R>(f: impl FnOnce(&mut Config) -> R) -> R {
+    CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut config = cell.get().clone();
+        let result = f(&mut config);
+        cell.set(config).expect("Failed to write config");
+        result
+    })
+}
+
+pub fn read_campaign<R>(
+    id: u64,
+    f: impl FnOnce(&Campaign) -> Result<R, FaucetError>,
+) -> Result<R, FaucetError> {
+    let campaign = CAMPAIGNS
+        .with(|campaigns| campaigns.borrow().get(id))
+        .ok_or(FaucetError::CampaignNotFound)?;
+    f(&campaign)
+}
+
+pub fn mutate_campaign<R>(
+    id: u64,
+    f: impl FnOnce(&mut Campaign) -> Result<R, FaucetError>,
+) -> Result<R, FaucetError> {
+    CAMPAIGNS.with(|campaigns| campaigns.borrow_mut().update(id, f))
+}
+
+// Reads the V1 `stable_save` blob, if stable memory still holds one. Must
+// run before any stable structure is touched, since initializing the memory
+// manager overwrites the start of stable memory.
+pub fn take_v1_state() -> Option<StateV1> {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return None;
+    }
+    let mut magic = [0; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    if &magic == b"MGR" {
+        return None;
+    }
+    let state = migrations::decode_v1(&ic_cdk::api::stable::stable_bytes())
+        .expect("Failed to decode V1 state");
+    Some(state)
+}
+
+// Empties the V2 claim history, oldest first
+pub fn take_v2_total_claims() -> Vec<(Principal, u64)> {
+    let memory = memory(TOTAL_CLAIMS_V2_MEMORY_ID);
+    if memory.size() == 0 {
+        return Vec::new();
+    }
+    let mut claims: StableBTreeMap<u64, (Principal, u64), Memory> = StableBTreeMap::init(memory);
+    let mut drained = Vec::with_capacity(claims.len() as usize);
+    while let Some((_, claim)) = claims.pop_first() {
+        drained.push(claim);
+    }
+    drained
+}
+
+// Empties the V2 claimed principal set
+pub fn take_v2_claimed_principals() -> Vec<Principal> {
+    let memory = memory(CLAIMED_PRINCIPALS_V2_MEMORY_ID);
+    if memory.size() == 0 {
+        return Vec::new();
+    }
+    let mut principals: StableBTreeMap<Principal, (), Memory> = StableBTreeMap::init(memory);
+    let mut drained = Vec::with_capacity(principals.len() as usize);
+    while let Some((principal, ())) = principals.pop_first() {
+        drained.push(principal);
+    }
+    drained
+}
+
+// Empties the V4 claimed principal map, with each principal's latest claim
+// time
+pub fn take_v4_claimed_principals() -> Vec<(Principal, u64)> {
+    let memory = memory(CLAIMED_PRINCIPALS_V4_MEMORY_ID);
+    if memory.size() == 0 {
+        return Vec::new();
+    }
+    let mut principals: StableBTreeMap<Principal, u64, Memory> = StableBTreeMap::init(memory);
+    let mut drained = Vec::with_capacity(principals.len() as usize);
+    while let Some(entry) = principals.pop_first() {
+        drained.push(entry);
+    }
+    drained
+}
+
+// Writes claim records from before V4 back in the latest version, so no
+// code a user submitted stays in stable memory. Must run before `CLAIMS` is
+// first used.
+pub fn rewrite_legacy_claim_records() {
+    let memory = memory(CLAIMS_MEMORY_ID);
+    if memory.size() == 0 {
+        return;
+    }
+    migrations::rewrite_legacy_claim_records(&mut StableBTreeMap::init(memory));
+}
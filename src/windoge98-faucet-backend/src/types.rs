@@ -0,0 +1,434 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::batches::BatchCode;
+use crate::ledger::LedgerKind;
+
+// Canister times are in nanoseconds since the epoch
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// The most items any paged query returns at once
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+// Returned to the caller of a successful claim
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimReceipt {
+    pub claim_id: u64,
+    pub block_index: Nat,
+    pub amount: u64,
+    pub extra_payouts: Vec<PayoutReceipt>,
+}
+
+// Where one of a claim's extra payouts stands: paid, still pending, or owed
+// after the ledger refused it
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PayoutReceipt {
+    pub journal_id: u64,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub status: TransferStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClaimStatus {
+    // Paid out; `block_index` holds the ledger block
+    Completed,
+    // Recorded before transfer metadata was kept, so there is no ledger
+    // block to audit it against
+    Legacy,
+}
+
+// A single claim. `timestamp` is nanoseconds since the epoch (0 for legacy
+// claims) and `fee` is the fee the payout was sent with, `None` meaning an
+// ICRC-1 ledger's own default.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClaimRecord {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub principal: Principal,
+    pub destination: Account,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub timestamp: u64,
+    pub ledger: Option<Principal>,
+    pub block_index: Option<Nat>,
+    pub status: ClaimStatus,
+    // Label of the campaign code the claim used; `None` for single-use batch
+    // codes and claims made before codes were labelled
+    pub code_label: Option<String>,
+}
+
+// Filters for `get_claims`; every field is optional
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ClaimQuery {
+    pub cursor: Option<u64>,
+    pub limit: Option<u64>,
+    pub principal: Option<Principal>,
+    pub campaign_id: Option<u64>,
+    pub code_label: Option<String>,
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+}
+
+// `next_cursor` is set when more claims may match; pass it back as
+// `ClaimQuery::cursor` to continue
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClaimPage {
+    pub claims: Vec<ClaimRecord>,
+    pub next_cursor: Option<u64>,
+}
+
+// One-shot: a principal claims once until custodians reset the registry.
+// Cooldown: a principal may claim again `seconds` after its last claim.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClaimMode {
+    #[default]
+    OneShot,
+    Cooldown {
+        seconds: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CampaignStatus {
+    Active,
+    #[default]
+    Paused,
+    // Final; a closed campaign can no longer be claimed, resumed or edited
+    Closed,
+}
+
+// A salted claim code; `hash` is SHA-256(salt || code)
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CodeHash {
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+// A code that changes every `step_seconds`: the 6-digit RFC 6238 TOTP
+// (HMAC-SHA-256) of `secret`. The code of the previous and next step is
+// accepted too.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RotatingCode {
+    pub secret: Vec<u8>,
+    pub step_seconds: u64,
+}
+
+// A code as custodians set it: in plaintext, hashed by the canister with a
+// fresh salt, already hashed with a salt of their choosing, or rotating
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CodeInput {
+    Plaintext(String),
+    Hashed(CodeHash),
+    Rotating(RotatingCode),
+}
+
+// A campaign's code as stored. Rotating codes need their secret to derive
+// the current code, so it is kept as is.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum CampaignCode {
+    Hashed(CodeHash),
+    Rotating(RotatingCode),
+}
+
+// How submitted codes are normalized before they are compared. Codes are
+// normalized the same way before they are hashed, so precomputed hashes must
+// be of the normalized code.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CodeNormalization {
+    // Strip leading and trailing whitespace
+    pub trim: bool,
+    // Compare case-insensitively
+    pub case_fold: bool,
+    // Unicode NFC, so composed and decomposed accents match
+    pub nfc: bool,
+}
+
+// One of a campaign's active codes, e.g. one per promo partner. The label
+// is recorded on every claim made with the code.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LabeledCode {
+    pub label: String,
+    pub code: CampaignCode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CodeEntry {
+    pub label: String,
+    pub code: CodeInput,
+}
+
+// A giveaway with its own codes, payout, budget and claimed set. Times are
+// nanoseconds since the epoch; `None` leaves that side of the window open.
+// Bumping `claims_epoch` forgets everyone who has claimed so far.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Campaign {
+    pub id: u64,
+    pub name: String,
+    // Any of these is accepted; with none, only batch codes are
+    pub codes: Vec<LabeledCode>,
+    pub normalization: CodeNormalization,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    // Cap on the total amount paid out, excluding fees
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    // Held by claims whose transfer is still in flight
+    pub reserved_amount: u64,
+    pub reserved_claims: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub claims_epoch: u64,
+    pub low_balance: Option<LowBalanceAlarm>,
+    pub extra_payouts: Vec<TokenPayout>,
+}
+
+// A payout on another ledger made alongside each claim's main one, once
+// that is paid. Budgets and balance alarms only cover the main payout.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenPayout {
+    pub ledger_canister_id: Principal,
+    pub ledger_kind: LedgerKind,
+    pub amount: u64,
+    pub transfer_fee: Option<u64>,
+}
+
+// Raised when the faucet's balance on a campaign's ledger drops below
+// `threshold`, in the ledger's base units. With `pause` the campaign is also
+// paused; it is not resumed once the balance recovers.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LowBalanceAlarm {
+    pub threshold: u64,
+    pub pause: bool,
+}
+
+// The faucet's balance on a campaign's ledger and how many claims it covers
+// at `per_claim`, the amount plus the transfer fee
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FaucetBalance {
+    pub campaign_id: u64,
+    pub ledger: Principal,
+    pub balance: Nat,
+    pub per_claim: u64,
+    pub claims_covered: u64,
+    pub low: bool,
+    pub checked_at: u64,
+}
+
+// Custodian-supplied settings for `create_campaign` and `update_campaign`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CampaignArgs {
+    pub name: String,
+    pub codes: Vec<CodeEntry>,
+    pub normalization: CodeNormalization,
+    pub amount: u64,
+    pub ledger_canister_id: Principal,
+    pub ledger_kind: LedgerKind,
+    pub transfer_fee: Option<u64>,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub low_balance: Option<LowBalanceAlarm>,
+    pub extra_payouts: Vec<TokenPayout>,
+}
+
+// Public view of a campaign; leaves out the codes
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CampaignInfo {
+    pub id: u64,
+    pub name: String,
+    pub normalization: CodeNormalization,
+    pub amount: u64,
+    pub ledger_canister_id: Option<Principal>,
+    pub ledger_kind: LedgerKind,
+    pub claim_mode: ClaimMode,
+    pub budget: Option<u64>,
+    pub max_claims: Option<u64>,
+    pub disbursed: u64,
+    pub claim_count: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub status: CampaignStatus,
+    pub low_balance: Option<LowBalanceAlarm>,
+    pub extra_payouts: Vec<TokenPayout>,
+}
+
+// A pending custodian-scheduled switch of a campaign on or off; `at` is
+// nanoseconds since the epoch
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledToggle {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub at: u64,
+    pub is_enabled: bool,
+}
+
+// Public view of a batch of single-use codes
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CodeBatchInfo {
+    pub id: u64,
+    pub campaign_id: u64,
+    pub size: u64,
+    pub remaining: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallerKind {
+    Anonymous,
+    // Derived from a public key, i.e. a user's identity
+    SelfAuthenticating,
+    Canister,
+    Other,
+}
+
+// Which callers may claim. Self-authenticating principals always may; with
+// `self_authenticating_only` set, no one else may.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallerPolicy {
+    pub allow_anonymous: bool,
+    pub allow_canisters: bool,
+    pub self_authenticating_only: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferStatus {
+    Pending,
+    Succeeded { block_index: Nat, claim_id: u64 },
+    Failed { message: String },
+    // An extra payout the ledger refused after its claim went through. The
+    // claim stands and the payout is kept for custodians to retry or write
+    // off.
+    Owed { message: String },
+}
+
+// A payout as journaled before its transfer is sent. The entry id is the
+// transfer memo and, with `created_at_time`, lets the ledger deduplicate
+// retries. A pending main payout holds its campaign budget and batch code;
+// once paid, an entry is journaled for each of its `extra_payouts`, pointing
+// back at it through `main_payout`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub campaign_id: u64,
+    // Who claimed; `destination` is the account that is paid
+    pub principal: Principal,
+    pub destination: Account,
+    pub ledger: Principal,
+    pub ledger_kind: LedgerKind,
+    pub amount: u64,
+    pub fee: Option<u64>,
+    pub created_at_time: u64,
+    pub code_label: Option<String>,
+    pub batch_code: Option<BatchCode>,
+    pub status: TransferStatus,
+    pub attempts: u64,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+    pub main_payout: Option<u64>,
+    pub extra_payouts: Vec<TokenPayout>,
+}
+
+// Where claims may be paid. Whoever is paid, claims are tracked against the
+// caller.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DestinationPolicy {
+    #[default]
+    AnyAccount,
+    // Only accounts owned by the caller, under any subaccount
+    CallerAccountsOnly,
+}
+
+// Failed code attempts a principal may make within `window_seconds` before
+// it is locked out. The first lockout lasts `lockout_seconds` and each
+// further one twice as long, up to `max_lockout_seconds`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub max_failures: u64,
+    pub window_seconds: u64,
+    pub lockout_seconds: u64,
+    pub max_lockout_seconds: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window_seconds: 10 * 60,
+            lockout_seconds: 60,
+            max_lockout_seconds: 24 * 60 * 60,
+        }
+    }
+}
+
+// A principal's failed code attempts; times are nanoseconds since the epoch
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Lockout {
+    pub principal: Principal,
+    pub failures: u64,
+    pub window_start: u64,
+    pub strikes: u64,
+    pub locked_until: u64,
+}
+
+// Which code of a batch (by its position) a principal redeemed, and when
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Redemption {
+    pub batch_id: u64,
+    pub serial: u64,
+    pub principal: Principal,
+    pub redeemed_at: u64,
+}
+
+// Returned once to the custodian who generated a batch; the canister only
+// keeps the hashes. `codes[i]` has serial `i`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GeneratedCodeBatch {
+    pub batch_id: u64,
+    pub codes: Vec<String>,
+}
+
+// Errors returned by claim and custodian endpoints
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum FaucetError {
+    Disabled,
+    InvalidCode,
+    CodeAlreadyRedeemed,
+    AlreadyClaimed,
+    // `next_eligible_at` is nanoseconds since the epoch
+    CooldownActive { next_eligible_at: u64 },
+    CampaignNotFound,
+    CampaignClosed,
+    CampaignNotStarted { start_time: u64 },
+    CampaignEnded,
+    BudgetExhausted,
+    ClaimLimitReached,
+    NotCustodian,
+    LedgerNotConfigured,
+    LedgerError { message: String },
+    InsufficientFaucetBalance { balance: Nat },
+    InvalidArgument { message: String },
+    RandomnessUnavailable { message: String },
+    // Too many wrong codes; `locked_until` is nanoseconds since the epoch
+    // and `strikes` the number of lockouts so far
+    LockedOut { locked_until: u64, strikes: u64 },
+    CallerNotAllowed { kind: CallerKind },
+    DestinationNotAllowed,
+    // Another claim from the same principal is awaiting the ledger
+    ClaimInProgress,
+    // The ledger call failed without saying whether the payout went
+    // through; the journal entry is retried until it does or is refused
+    TransferPending { journal_id: u64 },
+    // A transfer call for the journal entry is already awaiting the ledger
+    TransferInFlight { journal_id: u64 },
+    // The campaign's balance has not been checked since the last upgrade; the
+    // balance monitor checks every campaign with a ledger shortly after one
+    BalanceNotChecked,
+}